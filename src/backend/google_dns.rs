@@ -0,0 +1,104 @@
+use super::Backend;
+use anyhow::bail;
+use async_trait::async_trait;
+use serde::Deserialize;
+use tracing::*;
+
+const TXT: u16 = 16;
+
+const NOERROR: u32 = 0;
+const NXDOMAIN: u32 = 3;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Response {
+    status: u32,
+    #[serde(default)]
+    answer: Vec<Answer>,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    #[serde(rename = "type")]
+    kind: u16,
+    data: String,
+}
+
+/// Joins the strings of TXT data, which come quoted if there are several of them.
+fn txt_data(data: &str) -> String {
+    if data.starts_with('"') {
+        data.trim_matches('"').replace("\" \"", "")
+    } else {
+        data.to_string()
+    }
+}
+
+/// Backend querying Google Public DNS through its JSON API.
+pub struct GoogleDnsBackend {
+    client: reqwest::Client,
+    endpoint: String,
+    user_agent: String,
+}
+
+impl Default for GoogleDnsBackend {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: "https://dns.google/resolve".to_string(),
+            user_agent: concat!("dnsdisc/", env!("CARGO_PKG_VERSION")).to_string(),
+        }
+    }
+}
+
+impl GoogleDnsBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends queries to another server implementing the same API.
+    pub fn with_endpoint(&mut self, endpoint: impl Into<String>) -> &mut Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// Replaces the default `dnsdisc/<version>` user agent.
+    pub fn with_user_agent(&mut self, user_agent: impl Into<String>) -> &mut Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+}
+
+#[async_trait]
+impl Backend for GoogleDnsBackend {
+    async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+        trace!("Resolving FQDN {} via {}", fqdn, self.endpoint);
+        let response = self
+            .client
+            .get(&self.endpoint)
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .query(&[("name", fqdn.as_str()), ("type", "TXT")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Response>()
+            .await?;
+
+        match response.status {
+            NOERROR => {}
+            NXDOMAIN => return Ok(None),
+            status => bail!("Query for {} failed with status {}", fqdn, status),
+        }
+
+        // Answers may also include the CNAME records followed to get to the TXT record.
+        for answer in response.answer {
+            if answer.kind == TXT {
+                let data = txt_data(&answer.data);
+                if !data.is_empty() {
+                    return Ok(Some(data));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -15,3 +15,56 @@ impl Backend for HashMap<String, String> {
         Ok(None)
     }
 }
+
+/// In-memory backend for test fixtures, with optional wildcard-like lookups.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    records: HashMap<String, String>,
+    hierarchical_fallback: bool,
+}
+
+impl MemoryBackend {
+    pub fn new(records: HashMap<String, String>) -> Self {
+        Self {
+            records,
+            hierarchical_fallback: false,
+        }
+    }
+
+    /// Answers lookups of names without a record with the record of the closest parent
+    /// domain, e.g. `b.example.org` and then `example.org` for `a.b.example.org`.
+    pub fn with_hierarchical_fallback(&mut self, hierarchical_fallback: bool) -> &mut Self {
+        self.hierarchical_fallback = hierarchical_fallback;
+        self
+    }
+}
+
+impl From<HashMap<String, String>> for MemoryBackend {
+    fn from(records: HashMap<String, String>) -> Self {
+        Self::new(records)
+    }
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+        let mut name = fqdn.as_str();
+        loop {
+            if let Some(v) = self.records.get_record(name.to_string()).await? {
+                return Ok(Some(v));
+            }
+
+            if !self.hierarchical_fallback {
+                return Ok(None);
+            }
+
+            match name.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => {
+                    trace!("No record for {}, trying {}", name, parent);
+                    name = parent;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+}
@@ -3,6 +3,9 @@ use auto_impl::auto_impl;
 
 pub mod memory;
 
+#[cfg(feature = "google-dns")]
+pub mod google_dns;
+
 #[cfg(feature = "trust-dns")]
 pub mod trust_dns;
 
@@ -1,10 +1,21 @@
 use super::Backend;
+use anyhow::{anyhow, bail};
 use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tracing::*;
 use trust_dns_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
     error::{ResolveError, ResolveErrorKind},
     proto::DnsHandle,
-    AsyncResolver, ConnectionProvider,
+    AsyncResolver, ConnectionProvider, TokioAsyncResolver,
 };
 
 #[async_trait]
@@ -23,13 +34,270 @@ where
             }
             Ok(v) => {
                 if let Some(txt) = v.into_iter().next() {
-                    if let Some(txt_entry) = txt.iter().next() {
-                        return Ok(Some(String::from_utf8(txt_entry.to_vec())?));
+                    if !txt.txt_data().is_empty() {
+                        // Records longer than 255 bytes are split over several strings.
+                        return Ok(Some(String::from_utf8(txt.txt_data().concat())?));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Resolver options suited to crawling trees, i.e. bursts of hundreds of small TXT lookups:
+///
+/// - A 4096 record cache, large enough to hold a public tree.
+/// - A 3 second timeout, with 2 attempts.
+/// - EDNS enabled, so large records need not fall back to TCP.
+/// - No hosts file lookups, which never serve TXT records.
+///
+/// trust-dns 0.20 has no knobs for TCP retry on error or query name case randomization.
+pub fn tuned_resolver_opts() -> ResolverOpts {
+    ResolverOpts {
+        timeout: Duration::from_secs(3),
+        attempts: 2,
+        edns0: true,
+        cache_size: 4096,
+        use_hosts_file: false,
+        ..Default::default()
+    }
+}
+
+/// Builds a resolver for `config` using `tuned_resolver_opts`.
+pub fn tuned_resolver(config: ResolverConfig) -> anyhow::Result<TokioAsyncResolver> {
+    Ok(TokioAsyncResolver::tokio(config, tuned_resolver_opts())?)
+}
+
+/// Builds a resolver for the name servers at `servers`, which may listen on any port, using
+/// `tuned_resolver_opts`.
+pub fn tuned_resolver_for_servers(servers: &[SocketAddr]) -> anyhow::Result<TokioAsyncResolver> {
+    let mut name_servers = NameServerConfigGroup::new();
+    for addr in servers {
+        name_servers.merge(NameServerConfigGroup::from_ips_clear(
+            &[addr.ip()],
+            addr.port(),
+            true,
+        ));
+    }
+
+    tuned_resolver(ResolverConfig::from_parts(None, vec![], name_servers))
+}
+
+/// Builds a resolver that queries only `addr`, once per lookup.
+fn single_server_resolver(
+    addr: SocketAddr,
+    timeout: Duration,
+) -> anyhow::Result<TokioAsyncResolver> {
+    let config = ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+    );
+    let opts = ResolverOpts {
+        timeout,
+        attempts: 1,
+        ..Default::default()
+    };
+    Ok(TokioAsyncResolver::tokio(config, opts)?)
+}
+
+/// Backend that queries several name servers in order, falling back to the next one on failure.
+pub struct MultiserverTrustDnsBackend {
+    servers: Vec<(SocketAddr, TokioAsyncResolver)>,
+    timeout: Duration,
+}
+
+impl MultiserverTrustDnsBackend {
+    /// Queries `servers` with a 2 second timeout for each.
+    pub fn new(servers: Vec<SocketAddr>) -> anyhow::Result<Self> {
+        Self::new_with_timeout(servers, Duration::from_secs(2))
+    }
+
+    /// Queries `servers`, waiting `timeout` for each before moving on to the next one.
+    pub fn new_with_timeout(servers: Vec<SocketAddr>, timeout: Duration) -> anyhow::Result<Self> {
+        if servers.is_empty() {
+            bail!("No name servers given");
+        }
+
+        let servers = servers
+            .into_iter()
+            .map(|addr| Ok((addr, single_server_resolver(addr, timeout)?)))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { servers, timeout })
+    }
+}
+
+#[async_trait]
+impl Backend for MultiserverTrustDnsBackend {
+    async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+        let mut last_err = None;
+        for (addr, resolver) in &self.servers {
+            match tokio::time::timeout(self.timeout, resolver.get_record(fqdn.clone())).await {
+                Ok(Ok(v)) => return Ok(v),
+                Ok(Err(e)) => {
+                    debug!("Name server {} failed to resolve {}: {}", addr, fqdn, e);
+                    last_err = Some(e);
+                }
+                Err(_) => {
+                    debug!("Name server {} timed out resolving {}", addr, fqdn);
+                    last_err = Some(anyhow!("Timed out resolving {} via {}", fqdn, addr));
+                }
+            }
+        }
+
+        // `new` rejects empty server lists, so at least one server was tried.
+        Err(last_err.unwrap())
+    }
+}
+
+struct Zone {
+    servers: Vec<(SocketAddr, TokioAsyncResolver)>,
+    valid_until: Instant,
+    next: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+/// Backend that sends TXT queries straight to the authoritative name servers of each tree
+/// domain, bypassing the caches of recursive resolvers.
+///
+/// Name servers are looked up through the bootstrap resolver and cached until their NS
+/// records expire or they fail several lookups in a row. Queries rotate between them, and go
+/// to the bootstrap resolver if all of them fail.
+pub struct AuthoritativeBackend {
+    bootstrap: TokioAsyncResolver,
+    zones: Mutex<HashMap<String, Arc<Zone>>>,
+    port: u16,
+    timeout: Duration,
+    max_failures: usize,
+}
+
+impl AuthoritativeBackend {
+    pub fn new(bootstrap_resolver: TokioAsyncResolver) -> Self {
+        Self {
+            bootstrap: bootstrap_resolver,
+            zones: Default::default(),
+            port: 53,
+            timeout: Duration::from_secs(2),
+            max_failures: 3,
+        }
+    }
+
+    /// Port to query name servers on.
+    pub fn with_port(&mut self, port: u16) -> &mut Self {
+        self.port = port;
+        self
+    }
+
+    /// Time to wait for each name server before moving on to the next one.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Finds the closest enclosing zone of `fqdn` and its name servers.
+    async fn zone(&self, fqdn: &str) -> anyhow::Result<Option<(String, Arc<Zone>)>> {
+        let labels = fqdn.trim_end_matches('.').split('.').collect::<Vec<_>>();
+        let suffixes = (0..labels.len())
+            .map(|i| labels[i..].join("."))
+            .collect::<Vec<_>>();
+
+        {
+            let zones = self.zones.lock().unwrap();
+            for suffix in &suffixes {
+                if let Some(zone) = zones.get(suffix) {
+                    if zone.valid_until > Instant::now() {
+                        return Ok(Some((suffix.clone(), zone.clone())));
                     }
                 }
             }
         }
 
+        for suffix in suffixes {
+            let ns = match self.bootstrap.ns_lookup(format!("{}.", suffix)).await {
+                Ok(ns) => ns,
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut servers = vec![];
+            for name in ns.iter() {
+                match self.bootstrap.lookup_ip(name.clone()).await {
+                    Ok(ips) => {
+                        for ip in ips.iter() {
+                            let addr = SocketAddr::new(ip, self.port);
+                            servers.push((addr, single_server_resolver(addr, self.timeout)?));
+                        }
+                    }
+                    Err(e) => debug!("Failed to resolve name server {}: {}", name, e),
+                }
+            }
+            if servers.is_empty() {
+                return Ok(None);
+            }
+
+            trace!(
+                "Name servers of {}: {:?}",
+                suffix,
+                servers.iter().map(|(addr, _)| addr).collect::<Vec<_>>()
+            );
+            let zone = Arc::new(Zone {
+                servers,
+                valid_until: ns.valid_until(),
+                next: AtomicUsize::new(0),
+                failures: AtomicUsize::new(0),
+            });
+            self.zones
+                .lock()
+                .unwrap()
+                .insert(suffix.clone(), zone.clone());
+            return Ok(Some((suffix, zone)));
+        }
+
         Ok(None)
     }
 }
+
+#[async_trait]
+impl Backend for AuthoritativeBackend {
+    async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+        match self.zone(&fqdn).await {
+            Ok(Some((domain, zone))) => {
+                let start = zone.next.fetch_add(1, Ordering::Relaxed);
+                for i in 0..zone.servers.len() {
+                    let (addr, resolver) = &zone.servers[(start + i) % zone.servers.len()];
+                    match tokio::time::timeout(self.timeout, resolver.get_record(fqdn.clone()))
+                        .await
+                    {
+                        Ok(Ok(v)) => {
+                            zone.failures.store(0, Ordering::Relaxed);
+                            return Ok(v);
+                        }
+                        Ok(Err(e)) => {
+                            debug!("Name server {} failed to resolve {}: {}", addr, fqdn, e)
+                        }
+                        Err(_) => debug!("Name server {} timed out resolving {}", addr, fqdn),
+                    }
+                }
+
+                if zone.failures.fetch_add(1, Ordering::Relaxed) + 1 >= self.max_failures {
+                    debug!("Forgetting name servers of {}", domain);
+                    let mut zones = self.zones.lock().unwrap();
+                    if zones.get(&domain).map_or(false, |z| Arc::ptr_eq(z, &zone)) {
+                        zones.remove(&domain);
+                    }
+                }
+                debug!(
+                    "All name servers of {} failed, falling back to bootstrap resolver",
+                    domain
+                );
+            }
+            Ok(None) => trace!("No name servers found for {}", fqdn),
+            Err(e) => debug!("Failed to find name servers for {}: {}", fqdn, e),
+        }
+
+        self.bootstrap.get_record(fqdn).await
+    }
+}
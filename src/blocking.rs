@@ -0,0 +1,30 @@
+//! Synchronous access to queries, for consumers that cannot poll a `Stream`.
+
+use crate::QueryStream;
+use enr::{Enr, EnrKey};
+use tokio::runtime::Handle;
+use tokio_stream::StreamExt;
+
+/// Iterator over the ENRs of a query, blocking the calling thread on `handle` for each one.
+///
+/// Must not be used from within an asynchronous context of the runtime, where blocking would
+/// panic.
+pub struct EnrIterator<K: EnrKey> {
+    stream: QueryStream<K>,
+    handle: Handle,
+}
+
+impl<K: EnrKey> EnrIterator<K> {
+    pub fn new(stream: QueryStream<K>, handle: Handle) -> Self {
+        Self { stream, handle }
+    }
+}
+
+impl<K: EnrKey> Iterator for EnrIterator<K> {
+    type Item = anyhow::Result<Enr<K>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let stream = &mut self.stream;
+        self.handle.block_on(stream.next())
+    }
+}
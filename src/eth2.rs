@@ -0,0 +1,48 @@
+//! Consensus layer ENR entries, as published by beacon node trees.
+
+use enr::{Enr, EnrKey};
+use std::convert::TryInto;
+
+pub const ETH2_KEY: &str = "eth2";
+pub const ATTNETS_KEY: &str = "attnets";
+pub const SYNCNETS_KEY: &str = "syncnets";
+
+/// Decoded `eth2`, `attnets` and `syncnets` entries of an ENR.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Eth2Info {
+    pub fork_digest: [u8; 4],
+    pub next_fork_version: [u8; 4],
+    pub next_fork_epoch: u64,
+    /// Attestation subnet bitvector, `None` if absent or malformed.
+    pub attnets: Option<[u8; 8]>,
+    /// Sync committee subnet bitvector, `None` if absent or malformed.
+    pub syncnets: Option<u8>,
+}
+
+impl Eth2Info {
+    /// Returns `None` if the record has no well-formed `eth2` entry.
+    pub fn from_enr<K: EnrKey>(record: &Enr<K>) -> Option<Self> {
+        // SSZ-encoded ENRForkID: fork_digest, next_fork_version, next_fork_epoch.
+        let eth2 = record.get(ETH2_KEY)?;
+        if eth2.len() != 16 {
+            return None;
+        }
+
+        Some(Self {
+            fork_digest: eth2[..4].try_into().ok()?,
+            next_fork_version: eth2[4..8].try_into().ok()?,
+            next_fork_epoch: u64::from_le_bytes(eth2[8..].try_into().ok()?),
+            attnets: record.get(ATTNETS_KEY).and_then(|v| v.try_into().ok()),
+            syncnets: record
+                .get(SYNCNETS_KEY)
+                .filter(|v| v.len() == 1 && v[0] & 0xf0 == 0)
+                .map(|v| v[0]),
+        })
+    }
+
+    /// Number of attestation subnets the node subscribes to.
+    pub fn attnets_count(&self) -> Option<usize> {
+        self.attnets
+            .map(|attnets| attnets.iter().map(|b| b.count_ones() as usize).sum())
+    }
+}
@@ -0,0 +1,20 @@
+//! Ready-made predicates for `Resolver::with_filter`.
+
+use crate::eth2::Eth2Info;
+use enr::{Enr, EnrKey};
+
+/// Passes nodes on the fork with the given digest.
+pub fn eth2_fork_digest<K: EnrKey>(
+    fork_digest: [u8; 4],
+) -> impl Fn(&Enr<K>) -> bool + Send + Sync + 'static {
+    move |record| Eth2Info::from_enr(record).map_or(false, |info| info.fork_digest == fork_digest)
+}
+
+/// Passes nodes subscribed to at least `min` attestation subnets.
+pub fn min_attnets<K: EnrKey>(min: usize) -> impl Fn(&Enr<K>) -> bool + Send + Sync + 'static {
+    move |record| {
+        Eth2Info::from_enr(record)
+            .and_then(|info| info.attnets_count())
+            .map_or(false, |count| count >= min)
+    }
+}
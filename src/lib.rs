@@ -5,39 +5,355 @@ use bytes::Bytes;
 use data_encoding::*;
 use derive_more::{Deref, Display};
 use educe::Educe;
-use enr::{Enr, EnrKeyUnambiguous, EnrPublicKey};
-use maplit::hashset;
+use enr::{Enr, EnrKey, EnrKeyUnambiguous, EnrPublicKey, NodeId};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rlp::Rlp;
+use sha3::{Digest, Keccak256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
     fmt,
     fmt::{Display, Formatter},
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
     pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
 };
 use task_group::TaskGroup;
 use thiserror::Error;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    time::Instant,
+};
 use tokio_stream::{Stream, StreamExt};
 use tracing::*;
+use tracing_futures::Instrument;
 
 mod backend;
-pub use crate::backend::Backend;
+#[cfg(feature = "blocking")]
+mod blocking;
+#[cfg(feature = "eth2")]
+pub mod eth2;
+#[cfg(feature = "eth2")]
+pub mod filters;
+mod parser;
+pub mod raw;
+mod shared;
+#[cfg(feature = "google-dns")]
+pub use crate::backend::google_dns::GoogleDnsBackend;
+#[cfg(feature = "trust-dns")]
+pub use crate::backend::trust_dns::{
+    tuned_resolver, tuned_resolver_for_servers, tuned_resolver_opts, AuthoritativeBackend,
+    MultiserverTrustDnsBackend,
+};
+pub use crate::backend::{memory::MemoryBackend, Backend};
+#[cfg(feature = "blocking")]
+pub use crate::blocking::EnrIterator;
+pub use crate::shared::{LagPolicy, QueryExt, SharedItem, SharedQuery};
 
 type Base32Hash = ArrayString<[u8; BASE32_HASH_LEN]>;
 
 pub type QueryStream<K> = Pin<Box<dyn Stream<Item = anyhow::Result<Enr<K>>> + Send + 'static>>;
+pub type TimestampedQueryStream<K> =
+    Pin<Box<dyn Stream<Item = anyhow::Result<(Enr<K>, SystemTime)>> + Send + 'static>>;
+pub type OriginQueryStream<K> =
+    Pin<Box<dyn Stream<Item = anyhow::Result<(Enr<K>, Origin)>> + Send + 'static>>;
+pub type RawQueryStream = Pin<Box<dyn Stream<Item = anyhow::Result<String>> + Send + 'static>>;
+pub type DiagnosticStream = Pin<Box<dyn Stream<Item = Diagnostic> + Send + 'static>>;
 
 pub const BASE32_HASH_LEN: usize = 26;
+/// Longest record text the parser accepts.
+pub const MAX_RECORD_LEN: usize = 4096;
+/// Default limit on the size of TXT records, see `Resolver::with_max_txt_size`.
+pub const DEFAULT_MAX_TXT_SIZE: usize = MAX_RECORD_LEN;
 pub const ROOT_PREFIX: &str = "enrtree-root:v1";
 pub const LINK_PREFIX: &str = "enrtree://";
 pub const BRANCH_PREFIX: &str = "enrtree-branch:";
 pub const ENR_PREFIX: &str = "enr:";
 
+/// Record prefixes of a tree. Defaults to those of EIP-1459; private networks can use their
+/// own to keep clients from mixing their trees up with public ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordScheme {
+    pub root: String,
+    pub link: String,
+    pub branch: String,
+    pub enr: String,
+}
+
+impl RecordScheme {
+    /// Payload of `text` if it is an ENR leaf, checking prefixes in the order the parser does.
+    fn enr_payload<'a>(&self, text: &'a str) -> Option<&'a str> {
+        if [&self.root, &self.link, &self.branch]
+            .iter()
+            .any(|prefix| text.starts_with(prefix.as_str()))
+        {
+            return None;
+        }
+
+        text.strip_prefix(self.enr.as_str())
+    }
+}
+
+impl Default for RecordScheme {
+    fn default() -> Self {
+        Self {
+            root: ROOT_PREFIX.to_string(),
+            link: LINK_PREFIX.to_string(),
+            branch: BRANCH_PREFIX.to_string(),
+            enr: ENR_PREFIX.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidEnr {
+    /// A well-formed record with an identity scheme the key type does not support.
+    #[error("Unsupported ENR identity scheme: {0}")]
+    UnsupportedScheme(String),
+    #[error("Invalid Enr: {0}")]
+    Corrupt(String),
+}
+
+/// Decodes an ENR. The enr crate derives the node id from the public key while decoding, so a
+/// decoded record always carries the EIP-778 node id of its key.
+fn parse_enr<K: EnrKey>(s: &str) -> Result<Enr<K>, InvalidEnr> {
+    s.parse::<Enr<K>>()
+        .map_err(|e| match unsupported_scheme::<K>(s) {
+            Some(scheme) => InvalidEnr::UnsupportedScheme(scheme),
+            None => InvalidEnr::Corrupt(e),
+        })
+}
+
+/// Whether a base64 ENR payload is padded or uses the standard alphabet, which the enr crate
+/// rejects.
+fn is_sloppy_base64(payload: &str) -> bool {
+    payload.contains(&['=', '+', '/'][..])
+}
+
+fn is_base64url(payload: &str) -> bool {
+    payload
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Rewrites an ENR payload into unpadded base64url.
+fn normalize_enr_payload(payload: &str) -> String {
+    payload
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+/// Checks the payload of an ENR leaf, returning it as unpadded base64url. Padded and
+/// standard-alphabet payloads are normalized, unless `strict` is set, in which case they are
+/// rejected.
+fn check_enr_payload(payload: &str, strict: bool) -> Result<String, InvalidEnrEncoding> {
+    let payload = parser::run(payload, parser::enr_record_parser)
+        .map_err(|_| InvalidEnrEncoding(payload.to_string()))?;
+    let normalized = if is_sloppy_base64(payload) {
+        if strict {
+            return Err(InvalidEnrEncoding(payload.to_string()));
+        }
+        debug!("Normalizing ENR payload {}", payload);
+        normalize_enr_payload(payload)
+    } else {
+        payload.to_string()
+    };
+    if !is_base64url(&normalized) {
+        return Err(InvalidEnrEncoding(payload.to_string()));
+    }
+
+    Ok(normalized)
+}
+
+/// Returns the identity scheme of an ENR that is structurally valid but carries no public key
+/// that `K` can decode.
+fn unsupported_scheme<K: EnrKey>(s: &str) -> Option<String> {
+    let bytes = BASE64URL_NOPAD
+        .decode(s.strip_prefix(ENR_PREFIX)?.as_bytes())
+        .ok()?;
+    let rlp = Rlp::new(&bytes);
+    let mut content = BTreeMap::new();
+    for i in (2..rlp.item_count().ok()?).step_by(2) {
+        let value = rlp.at(i + 1).ok()?;
+        content.insert(
+            rlp.val_at::<Vec<u8>>(i).ok()?,
+            Bytes::copy_from_slice(value.as_raw()),
+        );
+    }
+
+    if K::enr_to_public(&content).is_ok() {
+        return None;
+    }
+
+    let id = Rlp::new(content.get(b"id".as_ref())?).data().ok()?;
+    Some(String::from_utf8_lossy(id).into_owned())
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+pub enum RecordKind {
+    Root,
+    Link,
+    Branch,
+    Enr,
+}
+
+/// A record that cannot appear at its position in the tree, e.g. an ENR in the link tree.
+#[derive(Debug, Error)]
+#[error("Unexpected {found} record at {fqdn}, expected {expected}")]
+pub struct WrongRecordKind {
+    pub fqdn: String,
+    pub label: Base32Hash,
+    /// Branch listing the record, `None` if referenced directly by the root.
+    pub parent: Option<Base32Hash>,
+    pub expected: RecordKind,
+    pub found: RecordKind,
+}
+
+/// A record whose hash does not match the label it is published under.
+#[derive(Debug, Error)]
+#[error("Record at {fqdn} hashes to {found}")]
+pub struct HashMismatch {
+    pub fqdn: String,
+    pub found: Base32Hash,
+}
+
+/// A TXT record larger than the resolver accepts, rejected before parsing.
+#[derive(Debug, Error)]
+#[error("TXT record at {fqdn} is {size} bytes long")]
+pub struct TxtTooLarge {
+    pub fqdn: String,
+    pub size: usize,
+}
+
+/// A crawl that parsed more records than allowed by `Resolver::with_max_records`.
+#[derive(Debug, Error)]
+#[error("Tree has more than {limit} records")]
+pub struct TreeTooLarge {
+    pub limit: usize,
+}
+
+/// A record skipped by `Resolver::query_with_diagnostics`, and why.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub fqdn: String,
+    pub error: anyhow::Error,
+}
+
+/// Tree an ENR was resolved from, see `Resolver::query_with_origin`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Origin {
+    pub domain: String,
+    /// Sequence number of the root that listed the ENR, `None` for fallback ENRs.
+    pub sequence: Option<usize>,
+}
+
+/// Record text longer than `MAX_RECORD_LEN`.
+#[derive(Debug, Error)]
+#[error("Record is {0} bytes long")]
+pub struct RecordTooLong(pub usize);
+
+/// A branch child that is shorter than a full hash label.
+#[derive(Debug, Error)]
+#[error("Branch child {0} is not {} characters long", BASE32_HASH_LEN)]
+pub struct InvalidHashLength(pub String);
+
+/// An ENR payload that is not base64. In strict mode, also one that is not unpadded base64url,
+/// as the ENR spec requires.
 #[derive(Debug, Error)]
-#[error("Invalid Enr: {0}")]
-pub struct InvalidEnr(String);
+#[error("Invalid ENR encoding: {0}")]
+pub struct InvalidEnrEncoding(pub String);
 
-fn debug_bytes(b: &Bytes, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+/// A root signature of a length no supported scheme produces.
+#[derive(Debug, Error)]
+#[error("Root signature is {0} bytes long, expected 64 or 65")]
+pub struct InvalidSignatureFormat(pub usize);
+
+/// A recovery id other than 0 or 1 at the end of a 65-byte secp256k1 signature.
+#[derive(Debug, Error)]
+#[error("Invalid recovery id {0}, expected 0 or 1")]
+pub struct InvalidRecoveryId(pub u8);
+
+fn check_signature_len(signature: &[u8]) -> Result<(), InvalidSignatureFormat> {
+    match signature.len() {
+        64 | 65 => Ok(()),
+        len => Err(InvalidSignatureFormat(len)),
+    }
+}
+
+/// Keys that can sign root records. secp256k1 roots carry the recovery id EIP-1459 requires on
+/// top of the 64-byte signature ENRs use.
+pub trait RootSigner: EnrKey {
+    /// Signs the text of a root record.
+    fn sign_root(&self, text: &str) -> anyhow::Result<Vec<u8>>;
+}
+
+#[cfg(feature = "k256")]
+impl RootSigner for enr::k256::ecdsa::SigningKey {
+    fn sign_root(&self, text: &str) -> anyhow::Result<Vec<u8>> {
+        use enr::k256::ecdsa::{recoverable, signature::DigestSigner};
+
+        let signature: recoverable::Signature = self
+            .try_sign_digest(Keccak256::new().chain(text))
+            .map_err(|e| anyhow!("Failed to sign root: {}", e))?;
+
+        Ok(signature.as_ref().to_vec())
+    }
+}
+
+#[cfg(all(feature = "ed25519", feature = "k256"))]
+impl RootSigner for enr::ed25519_dalek::Keypair {
+    fn sign_root(&self, text: &str) -> anyhow::Result<Vec<u8>> {
+        self.sign_v4(text.as_bytes())
+            .map_err(|e| anyhow!("Failed to sign root: {:?}", e))
+    }
+}
+
+#[cfg(all(feature = "ed25519", feature = "k256"))]
+impl RootSigner for enr::CombinedKey {
+    fn sign_root(&self, text: &str) -> anyhow::Result<Vec<u8>> {
+        match self {
+            enr::CombinedKey::Secp256k1(key) => key.sign_root(text),
+            enr::CombinedKey::Ed25519(key) => key.sign_root(text),
+        }
+    }
+}
+
+fn check_txt_size(
+    fqdn: String,
+    record: Option<String>,
+    max_txt_size: usize,
+) -> anyhow::Result<Option<String>> {
+    match record {
+        Some(text) if text.len() > max_txt_size => Err(TxtTooLarge {
+            fqdn,
+            size: text.len(),
+        }
+        .into()),
+        record => Ok(record),
+    }
+}
+
+/// Computes the subdomain label under which a record with the given text is published.
+fn record_hash(record: &str) -> Base32Hash {
+    Base32Hash::from(&BASE32_NOPAD.encode(&content_hash(record)[..16])).unwrap()
+}
+
+/// Full digest of a record, of which its label is a truncation. Sets of records are keyed on
+/// it, so that records with colliding labels are kept apart.
+fn content_hash(record: &str) -> [u8; 32] {
+    Keccak256::digest(record.as_bytes()).into()
+}
+
+fn debug_bytes(b: &[u8], f: &mut std::fmt::Formatter) -> std::fmt::Result {
     write!(f, "{}", hex::encode(b))
 }
 
@@ -47,35 +363,157 @@ pub struct RootRecord {
     #[deref]
     base: UnsignedRoot,
     #[educe(Debug(method = "debug_bytes"))]
-    signature: Bytes,
+    signature: Box<[u8]>,
 }
 
-#[derive(Clone, Debug, Display)]
-#[display(
-    fmt = "{} e={} l={} seq={}",
-    ROOT_PREFIX,
-    enr_root,
-    link_root,
-    sequence
-)]
+#[derive(Clone, Debug)]
 pub struct UnsignedRoot {
+    /// Root prefix of the scheme the record was parsed with. Covered by the signature.
+    prefix: String,
     enr_root: Base32Hash,
     link_root: Base32Hash,
     sequence: usize,
+    /// Unrecognized `key=value` entries, in the order they were published. Written back after
+    /// `seq`, so the signature only verifies if they were published there.
+    extra: Box<[(String, String)]>,
+}
+
+impl Display for UnsignedRoot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} e={} l={} seq={}",
+            self.prefix, self.enr_root, self.link_root, self.sequence
+        )?;
+        for (key, value) in &self.extra {
+            write!(f, " {}={}", key, value)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl RootRecord {
     fn verify<K: EnrKeyUnambiguous>(&self, pk: &K::PublicKey) -> anyhow::Result<()> {
-        let mut sig = self.signature.clone();
-
-        // TODO: find way to unify with ed25519 sigs
-        sig.truncate(64);
-        if !pk.verify_v4(self.base.to_string().as_bytes(), &sig) {
+        check_signature_len(&self.signature)?;
+        // secp256k1 signatures end with a recovery id, which is not needed to verify them
+        // against a known key. ed25519 signatures are 64 bytes long to begin with.
+        let sig = &self.signature[..self.signature.len().min(64)];
+        if !pk.verify_v4(self.base.to_string().as_bytes(), sig) {
             bail!("Public key does not match");
         }
 
         Ok(())
     }
+
+    /// Builds and signs the next version of this root, pointing at new subtrees.
+    pub fn increment_sequence<K: RootSigner>(
+        self,
+        new_enr_root: Base32Hash,
+        new_link_root: Base32Hash,
+        key: &K,
+    ) -> anyhow::Result<RootRecord> {
+        let base = self.base.next_sequence(new_enr_root, new_link_root);
+        let signature = key.sign_root(&base.to_string())?;
+
+        Ok(RootRecord {
+            base,
+            signature: signature.into(),
+        })
+    }
+
+    /// Assembles a root from a secp256k1 signature made elsewhere, e.g. by a remote signer,
+    /// over the text of `base`. The signature is `r || s || v`, with a recovery id of 0 or 1.
+    pub fn from_parts(base: UnsignedRoot, signature: &[u8; 65]) -> anyhow::Result<Self> {
+        let recovery_id = signature[64];
+        if recovery_id > 1 {
+            return Err(InvalidRecoveryId(recovery_id).into());
+        }
+
+        Ok(RootRecord {
+            base,
+            signature: signature.to_vec().into(),
+        })
+    }
+
+    /// The `r || s || v` form of the signature. `None` for 64-byte signatures, which carry no
+    /// recovery id: ed25519 ones and secp256k1 ones published without it.
+    pub fn signature_bytes(&self) -> Option<[u8; 65]> {
+        <[u8; 65]>::try_from(&*self.signature).ok()
+    }
+
+    /// Raw signature bytes, as published in the record.
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+
+    /// Like `from_parts`, for signers that return the recovery id next to a plain secp256k1
+    /// signature.
+    #[cfg(feature = "k256")]
+    pub fn from_ecdsa_parts(
+        base: UnsignedRoot,
+        signature: &enr::k256::ecdsa::Signature,
+        recovery_id: u8,
+    ) -> anyhow::Result<Self> {
+        let mut bytes = [0; 65];
+        bytes[..64].copy_from_slice(signature.as_ref());
+        bytes[64] = recovery_id;
+        Self::from_parts(base, &bytes)
+    }
+
+    /// Splits a 65-byte secp256k1 signature into the plain signature and its recovery id.
+    #[cfg(feature = "k256")]
+    pub fn ecdsa_parts(&self) -> anyhow::Result<(enr::k256::ecdsa::Signature, u8)> {
+        let bytes = self
+            .signature_bytes()
+            .ok_or_else(|| anyhow!("Root signature has no recovery id"))?;
+        let signature = enr::k256::ecdsa::Signature::try_from(&bytes[..64])
+            .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+
+        Ok((signature, bytes[64]))
+    }
+
+    /// Recovers the secp256k1 key that signed this root, for trees whose key is not known
+    /// upfront. Requires the 65-byte form of the signature, which carries the recovery id.
+    #[cfg(feature = "k256")]
+    pub fn recover_key(&self) -> anyhow::Result<enr::k256::ecdsa::VerifyingKey> {
+        use enr::k256::ecdsa::recoverable;
+
+        let signature = recoverable::Signature::try_from(&*self.signature)
+            .map_err(|e| anyhow!("Invalid recoverable signature: {}", e))?;
+        signature
+            .recover_verify_key_from_digest(Keccak256::new().chain(self.base.to_string()))
+            .map_err(|e| anyhow!("Failed to recover public key: {}", e))
+    }
+}
+
+impl UnsignedRoot {
+    /// A root of a new tree pointing at the given subtrees. Sign its text to publish it.
+    pub fn new(enr_root: Base32Hash, link_root: Base32Hash, sequence: usize) -> Self {
+        Self {
+            prefix: ROOT_PREFIX.to_string(),
+            enr_root,
+            link_root,
+            sequence,
+            extra: Default::default(),
+        }
+    }
+
+    /// The next version of this root, pointing at new subtrees. Sign its text to publish it.
+    pub fn next_sequence(&self, new_enr_root: Base32Hash, new_link_root: Base32Hash) -> Self {
+        Self {
+            prefix: self.prefix.clone(),
+            enr_root: new_enr_root,
+            link_root: new_link_root,
+            sequence: self.sequence + 1,
+            extra: self.extra.clone(),
+        }
+    }
+
+    /// Entries of the root that are not part of EIP-1459, such as `meta=`.
+    pub fn extra(&self) -> &[(String, String)] {
+        &self.extra
+    }
 }
 
 impl Display for RootRecord {
@@ -84,17 +522,17 @@ impl Display for RootRecord {
             f,
             "{} sig={}",
             self.base,
-            BASE64.encode(self.signature.as_ref())
+            BASE64URL_NOPAD.encode(self.signature.as_ref())
         )
     }
 }
 
 #[derive(Clone, Educe)]
 #[educe(Debug)]
-pub enum DnsRecord<K: EnrKeyUnambiguous> {
+pub enum DnsRecord<K: EnrKey, A: EnrKeyUnambiguous = K> {
     Root(RootRecord),
     Link {
-        public_key: K::PublicKey,
+        public_key: A::PublicKey,
         domain: String,
     },
     Branch {
@@ -105,66 +543,195 @@ pub enum DnsRecord<K: EnrKeyUnambiguous> {
     },
 }
 
-impl<K: EnrKeyUnambiguous> Display for DnsRecord<K> {
+/// Defaults to an empty branch, the only record with a meaningful empty state.
+impl<K: EnrKey, A: EnrKeyUnambiguous> Default for DnsRecord<K, A> {
+    fn default() -> Self {
+        Self::Branch {
+            children: HashSet::new(),
+        }
+    }
+}
+
+impl<K: EnrKey, A: EnrKeyUnambiguous> DnsRecord<K, A> {
+    /// Branch of the first `limit` distinct children, in iteration order.
+    pub fn branch_with_limit(children: impl IntoIterator<Item = Base32Hash>, limit: usize) -> Self {
+        let mut branch = HashSet::new();
+        for child in children {
+            if branch.len() == limit {
+                break;
+            }
+            branch.insert(child);
+        }
+
+        Self::Branch { children: branch }
+    }
+
+    pub fn kind(&self) -> RecordKind {
+        match self {
+            Self::Root(_) => RecordKind::Root,
+            Self::Link { .. } => RecordKind::Link,
+            Self::Branch { .. } => RecordKind::Branch,
+            Self::Enr { .. } => RecordKind::Enr,
+        }
+    }
+
+    pub fn try_into_root(self) -> Result<RootRecord, Self> {
+        if let Self::Root(root_record) = self {
+            Ok(root_record)
+        } else {
+            Err(self)
+        }
+    }
+
+    pub fn try_into_link(self) -> Result<(A::PublicKey, String), Self> {
+        if let Self::Link { public_key, domain } = self {
+            Ok((public_key, domain))
+        } else {
+            Err(self)
+        }
+    }
+
+    pub fn try_into_branch(self) -> Result<HashSet<Base32Hash>, Self> {
+        if let Self::Branch { children } = self {
+            Ok(children)
+        } else {
+            Err(self)
+        }
+    }
+
+    pub fn try_into_enr(self) -> Result<Enr<K>, Self> {
+        if let Self::Enr { record } = self {
+            Ok(record)
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Socket addresses an ENR record can be reached at, over IPv4 and IPv6, TCP and UDP.
+    /// Empty for other kinds of records.
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            Self::Enr { record } => socket_addrs(record),
+            _ => vec![],
+        }
+    }
+}
+
+impl<K: EnrKey, A: EnrKeyUnambiguous> Display for DnsRecord<K, A> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_with_scheme(&RecordScheme::default()))
+    }
+}
+
+/// Compares the serialized records, as public keys cannot be compared directly.
+impl<K: EnrKey, A: EnrKeyUnambiguous> PartialEq for DnsRecord<K, A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl<K: EnrKey, A: EnrKeyUnambiguous> Eq for DnsRecord<K, A> {}
+
+/// Hashes the serialized record, which costs time linear in its size. Public keys do not
+/// implement `Hash`, so there is no structural alternative.
+impl<K: EnrKey, A: EnrKeyUnambiguous> Hash for DnsRecord<K, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.to_string().as_bytes().hash(state)
+    }
+}
+
+impl<K: EnrKey, A: EnrKeyUnambiguous> FromStr for DnsRecord<K, A> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, false, &RecordScheme::default())
+    }
+}
+
+impl<K: EnrKey, A: EnrKeyUnambiguous> DnsRecord<K, A> {
+    /// Like `from_str`, but rejects branches with empty children, such as ones left by a
+    /// trailing comma, and ENRs that are padded or use the standard base64 alphabet instead of
+    /// normalizing them.
+    pub fn parse_strict(s: &str) -> anyhow::Result<Self> {
+        Self::parse(s, true, &RecordScheme::default())
+    }
+
+    /// Parses a record published with the prefixes of `scheme`.
+    pub fn parse_with_scheme(s: &str, scheme: &RecordScheme) -> anyhow::Result<Self> {
+        Self::parse(s, false, scheme)
+    }
+
+    /// Parses a record split over several TXT strings, which are joined with no separator.
+    pub fn from_concatenated(parts: &[&str]) -> anyhow::Result<Self> {
+        parts.concat().parse()
+    }
+
+    /// Serializes the record with the prefixes of `scheme`. Roots keep the prefix they were
+    /// parsed with, as it is covered by their signature.
+    pub fn to_string_with_scheme(&self, scheme: &RecordScheme) -> String {
         match self {
-            Self::Root(root_record) => write!(f, "{}", root_record),
-            Self::Link { public_key, domain } => write!(
-                f,
+            Self::Root(root_record) => root_record.to_string(),
+            Self::Link { public_key, domain } => format!(
                 "{}{}@{}",
-                LINK_PREFIX,
+                scheme.link,
                 BASE32_NOPAD.encode(public_key.encode_uncompressed().as_ref()),
                 domain
             ),
-            Self::Branch { children } => write!(
-                f,
-                "{}{}",
-                BRANCH_PREFIX,
-                children
-                    .iter()
-                    .map(ToString::to_string)
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ),
-            Self::Enr { record } => write!(f, "{}", record.to_base64()),
+            Self::Branch { children } => {
+                let mut children = children.iter().map(ToString::to_string).collect::<Vec<_>>();
+                children.sort_unstable();
+                format!("{}{}", scheme.branch, children.join(","))
+            }
+            Self::Enr { record } => {
+                format!("{}{}", scheme.enr, &record.to_base64()[ENR_PREFIX.len()..])
+            }
         }
     }
-}
 
-impl<K: EnrKeyUnambiguous> FromStr for DnsRecord<K> {
-    type Err = anyhow::Error;
+    fn parse(s: &str, strict: bool, scheme: &RecordScheme) -> anyhow::Result<Self> {
+        if s.len() > MAX_RECORD_LEN {
+            return Err(RecordTooLong(s.len()).into());
+        }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
         trace!("Parsing record {}", s);
-        if let Some(root) = s.strip_prefix(ROOT_PREFIX) {
+        if let Some(root) = s.strip_prefix(scheme.root.as_str()) {
             let mut e = None;
             let mut l = None;
             let mut seq = None;
             let mut sig = None;
-            for entry in root.trim().split_whitespace() {
-                if let Some(v) = entry.strip_prefix("e=") {
-                    trace!("Extracting ENR root: {:?}", v);
-                    e = Some(v.parse()?);
-                } else if let Some(v) = entry.strip_prefix("l=") {
-                    trace!("Extracting link root: {:?}", v);
-                    l = Some(v.parse()?);
-                } else if let Some(v) = entry.strip_prefix("seq=") {
-                    trace!("Extracting sequence: {:?}", v);
-                    seq = Some(v.parse()?);
-                } else if let Some(v) = entry.strip_prefix("sig=") {
-                    trace!("Extracting signature: {:?}", v);
-                    let v = BASE64URL_NOPAD.decode(v.as_bytes())?.into();
-                    sig = Some(v);
-                } else {
-                    bail!("Invalid string: {}", entry);
+            let mut extra = vec![];
+            for (key, value) in parser::run(root, parser::root_record_parser)? {
+                match key {
+                    "e" => {
+                        trace!("Extracting ENR root: {:?}", value);
+                        e = Some(value.parse()?);
+                    }
+                    "l" => {
+                        trace!("Extracting link root: {:?}", value);
+                        l = Some(value.parse()?);
+                    }
+                    "seq" => {
+                        trace!("Extracting sequence: {:?}", value);
+                        seq = Some(value.parse()?);
+                    }
+                    "sig" => {
+                        trace!("Extracting signature: {:?}", value);
+                        sig = Some(BASE64URL_NOPAD.decode(value.as_bytes())?.into());
+                    }
+                    _ => {
+                        trace!("Extracting extra entry: {}={}", key, value);
+                        extra.push((key.to_string(), value.to_string()));
+                    }
                 }
             }
 
             let v = RootRecord {
                 base: UnsignedRoot {
+                    prefix: scheme.root.clone(),
                     enr_root: e.ok_or_else(|| anyhow!("ENR root absent"))?,
                     link_root: l.ok_or_else(|| anyhow!("Link root absent"))?,
                     sequence: seq.ok_or_else(|| anyhow!("Sequence not found"))?,
+                    extra: extra.into(),
                 },
                 signature: sig.ok_or_else(|| anyhow!("Signature not found"))?,
             };
@@ -174,31 +741,28 @@ impl<K: EnrKeyUnambiguous> FromStr for DnsRecord<K> {
             return Ok(DnsRecord::Root(v));
         }
 
-        if let Some(link) = s.strip_prefix(LINK_PREFIX) {
-            let mut it = link.split('@');
-            let public_key = K::decode_public(
-                &BASE32_NOPAD.decode(
-                    &it.next()
-                        .ok_or_else(|| anyhow!("Public key not found"))?
-                        .as_bytes(),
-                )?,
-            )?;
-            let domain = it
-                .next()
-                .ok_or_else(|| anyhow!("Domain not found"))?
-                .to_string();
+        if let Some(link) = s.strip_prefix(scheme.link.as_str()) {
+            let (public_key, domain) = parser::run(link, parser::link_record_parser)?;
+            let public_key = A::decode_public(&BASE32_NOPAD.decode(public_key.as_bytes())?)?;
+            let domain = domain.to_string();
 
             return Ok(DnsRecord::Link { public_key, domain });
         }
 
-        if let Some(branch) = s.strip_prefix(BRANCH_PREFIX) {
-            let children = branch
-                .trim()
-                .split(',')
+        if let Some(branch) = s.strip_prefix(scheme.branch.as_str()) {
+            let branch = branch.trim();
+            let children = parser::run(branch, parser::branch_record_parser)?
+                .into_iter()
                 .filter_map(|h| match h.parse::<Base32Hash>() {
                     Ok(v) => {
                         if v.is_empty() {
-                            None
+                            if strict && !branch.is_empty() {
+                                Some(Err(anyhow!("Empty child in branch: {}", s)))
+                            } else {
+                                None
+                            }
+                        } else if v.len() != BASE32_HASH_LEN {
+                            Some(Err(InvalidHashLength(v.to_string()).into()))
                         } else {
                             Some(Ok(v))
                         }
@@ -210,8 +774,9 @@ impl<K: EnrKeyUnambiguous> FromStr for DnsRecord<K> {
             return Ok(DnsRecord::Branch { children });
         }
 
-        if s.starts_with(ENR_PREFIX) {
-            let record = s.parse::<Enr<K>>().map_err(InvalidEnr)?;
+        if let Some(enr) = s.strip_prefix(scheme.enr.as_str()) {
+            let payload = check_enr_payload(enr, strict)?;
+            let record = parse_enr(&format!("{}{}", ENR_PREFIX, payload))?;
 
             return Ok(DnsRecord::Enr { record });
         }
@@ -220,10 +785,10 @@ impl<K: EnrKeyUnambiguous> FromStr for DnsRecord<K> {
     }
 }
 
-fn domain_is_allowed<K: EnrKeyUnambiguous>(
-    whitelist: &Option<Arc<HashMap<String, K::PublicKey>>>,
+fn domain_is_allowed<P: EnrPublicKey>(
+    whitelist: &Option<Arc<HashMap<String, P>>>,
     domain: &str,
-    public_key: &K::PublicKey,
+    public_key: &P,
 ) -> bool {
     whitelist.as_ref().map_or(true, |whitelist| {
         whitelist.get(domain).map_or(false, |pk| {
@@ -232,333 +797,4991 @@ fn domain_is_allowed<K: EnrKeyUnambiguous>(
     })
 }
 
+/// Whether a link points back at the tree it was found in, signed by `tree_key`.
+fn is_self_link<P: EnrPublicKey>(
+    host: &str,
+    tree_key: Option<&P>,
+    domain: &str,
+    public_key: &P,
+) -> bool {
+    domain.eq_ignore_ascii_case(host)
+        && tree_key.map_or(false, |pk| {
+            pk.encode().as_ref() == public_key.encode().as_ref()
+        })
+}
+
 #[derive(Clone, Debug)]
 enum BranchKind<K: EnrPublicKey> {
-    Enr,
+    Enr {
+        /// Applies to links found among the ENRs, which are only followed on request.
+        remote_whitelist: Option<Arc<HashMap<String, K>>>,
+    },
     Link {
         remote_whitelist: Option<Arc<HashMap<String, K>>>,
     },
 }
 
-fn resolve_branch<B: Backend, K: EnrKeyUnambiguous>(
-    task_group: Arc<TaskGroup>,
-    backend: Arc<B>,
-    host: String,
-    children: HashSet<Base32Hash>,
-    kind: BranchKind<K::PublicKey>,
-) -> QueryStream<K> {
-    let (tx, mut branches_res) = tokio::sync::mpsc::channel(1);
-    for subdomain in &children {
-        let fqdn = format!("{}.{}", subdomain, host);
-        task_group.spawn_with_name(format!("DNS discovery: {}", fqdn), {
-            let subdomain = *subdomain;
-            let tx = tx.clone();
-            let backend = backend.clone();
-            let host = host.clone();
-            let kind = kind.clone();
-            let fqdn = fqdn.clone();
-            let task_group = task_group.clone();
-            async move {
-                if let Err(e) = {
-                    let tx = tx.clone();
-                    async move {
-                        let record = backend.get_record(fqdn).await?;
-                        if let Some(record) = record {
-                            trace!("Resolved record {}: {:?}", subdomain, record);
-                            let record = record.parse()?;
-                            match record {
-                                DnsRecord::Branch { children } => {
-                                    let mut t =
-                                        resolve_branch(task_group, backend, host, children, kind);
-                                    while let Some(item) = t.try_next().await? {
-                                        let _ = tx.send(Ok(item)).await;
-                                    }
+impl<K: EnrPublicKey> BranchKind<K> {
+    fn leaf_kind(&self) -> RecordKind {
+        match self {
+            Self::Enr { .. } => RecordKind::Enr,
+            Self::Link { .. } => RecordKind::Link,
+        }
+    }
 
-                                    return Ok(());
-                                }
-                                DnsRecord::Link { public_key, domain } => {
-                                    if let BranchKind::Link { remote_whitelist } = &kind {
-                                        if domain_is_allowed::<K>(
-                                            &remote_whitelist,
-                                            &domain,
-                                            &public_key,
-                                        ) {
-                                            let mut t = resolve_tree(
-                                                Some(task_group),
-                                                backend,
-                                                domain,
-                                                Some(public_key),
-                                                None,
-                                                remote_whitelist.clone(),
-                                            );
-                                            while let Some(item) = t.try_next().await? {
-                                                let _ = tx.send(Ok(item)).await;
-                                            }
-                                        } else {
-                                            trace!(
-                                                "Skipping subtree for forbidden domain: {}",
-                                                domain
-                                            );
-                                        }
-                                        return Ok(());
-                                    } else {
-                                        return Err(anyhow!(
-                                            "Unexpected link record in ENR tree: {}",
-                                            subdomain
-                                        ));
-                                    }
-                                }
-                                DnsRecord::Enr { record } => {
-                                    if let BranchKind::Enr = &kind {
-                                        let _ = tx.send(Ok(record)).await;
-
-                                        return Ok(());
-                                    } else {
-                                        return Err(anyhow!(
-                                            "Unexpected ENR record in link tree: {}",
-                                            subdomain
-                                        ));
-                                    }
-                                }
-                                DnsRecord::Root { .. } => {
-                                    return Err(anyhow!("Unexpected root record: {}", subdomain));
-                                }
-                            }
-                        } else {
-                            warn!("Child {} is empty", subdomain);
-                        }
+    /// Whitelist for links found in this branch, `None` if links may not appear in it.
+    fn link_whitelist(
+        &self,
+        follow_inline_links: bool,
+    ) -> Option<&Option<Arc<HashMap<String, K>>>> {
+        match self {
+            Self::Link { remote_whitelist } => Some(remote_whitelist),
+            Self::Enr { remote_whitelist } if follow_inline_links => Some(remote_whitelist),
+            Self::Enr { .. } => None,
+        }
+    }
+}
 
-                        Ok(())
-                    }
-                }
-                .await
-                {
-                    let _ = tx.send(Err(e)).await;
-                }
-            }
-        });
+/// How repeated ENRs are collapsed within a single query.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupMode {
+    /// Yield every ENR found in the tree.
+    Off,
+    /// Skip ENRs byte-identical to one already yielded.
+    ExactDuplicatesOnly,
+    /// Yield an ENR only if its node is new or its sequence number is higher than that of
+    /// any version of the node yielded so far.
+    #[default]
+    ByNodeIdKeepNewest,
+}
+
+/// A condition on the entries of an ENR. Values are compared against the raw RLP encoding
+/// stored in the record, so an empty value present under a key is `[0x80]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnrRequirement {
+    /// The key must be present and, if `value` is set, hold exactly that RLP value.
+    Entry { key: String, value: Option<Vec<u8>> },
+    /// At least one of the requirements must hold.
+    AnyOf(Vec<EnrRequirement>),
+}
+
+impl EnrRequirement {
+    pub fn has_key(key: impl Into<String>) -> Self {
+        Self::Entry {
+            key: key.into(),
+            value: None,
+        }
     }
 
-    Box::pin(stream! {
-        trace!("Resolving branch {:?}", children);
-        while let Some(v) = branches_res.recv().await {
-            yield v;
+    pub fn has_value(key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        Self::Entry {
+            key: key.into(),
+            value: Some(value.into()),
         }
-        trace!("Branch {:?} resolution complete", children);
-    })
+    }
+
+    pub fn any_of(requirements: impl IntoIterator<Item = EnrRequirement>) -> Self {
+        Self::AnyOf(requirements.into_iter().collect())
+    }
+
+    pub fn matches<K: EnrKey>(&self, record: &Enr<K>) -> bool {
+        match self {
+            Self::Entry { key, value } => match (record.get_raw_rlp(key), value) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(found), Some(value)) => found == value.as_slice(),
+            },
+            Self::AnyOf(requirements) => requirements.iter().any(|r| r.matches(record)),
+        }
+    }
 }
 
-fn resolve_tree<B: Backend, K: EnrKeyUnambiguous>(
-    task_group: Option<Arc<TaskGroup>>,
-    backend: Arc<B>,
-    host: String,
-    public_key: Option<K::PublicKey>,
-    seen_sequence: Option<usize>,
-    remote_whitelist: Option<Arc<HashMap<String, K::PublicKey>>>,
-) -> QueryStream<K> {
-    Box::pin(try_stream! {
-        let task_group = task_group.unwrap_or_default();
-        let record = backend.get_record(host.clone()).await?;
-        if let Some(record) = &record {
-            let record = DnsRecord::<K>::from_str(&record)?;
-            if let DnsRecord::Root(record) = &record {
-                if let Some(pk) = public_key {
-                    record.verify::<K>(&pk)?;
-                }
+/// Timeouts for fetching each kind of record. The kind of a record below the root is only
+/// known once it arrives, so those lookups wait for the longer of `branch` and `leaf`, and
+/// records that took longer than the timeout for their kind are then skipped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordTimeouts {
+    pub root: Duration,
+    pub branch: Duration,
+    /// Applies to both ENR and link records.
+    pub leaf: Duration,
+}
+
+impl RecordTimeouts {
+    pub fn uniform(timeout: Duration) -> Self {
+        Self {
+            root: timeout,
+            branch: timeout,
+            leaf: timeout,
+        }
+    }
 
-                let UnsignedRoot { enr_root, link_root, sequence } = &record.base;
+    fn for_kind(&self, kind: RecordKind) -> Duration {
+        match kind {
+            RecordKind::Root => self.root,
+            RecordKind::Branch => self.branch,
+            RecordKind::Link | RecordKind::Enr => self.leaf,
+        }
+    }
+}
 
-                if let Some(seen) = seen_sequence {
-                    if *sequence <= seen {
-                        // We have already seen this record.
-                        return;
-                    }
-                }
+/// Token bucket capping the rate of DNS lookups. Lookups wait for a token instead of failing
+/// once the budget is spent.
+#[derive(Debug)]
+pub struct QueryBudget {
+    per_sec: f64,
+    burst: f64,
+    /// Tokens left as of the last refill, and the time of the refill.
+    state: tokio::sync::Mutex<(f64, Instant)>,
+}
 
-                let mut s = resolve_branch(task_group.clone(), backend.clone(), host.clone(), hashset![ *link_root ], BranchKind::Link { remote_whitelist });
-                while let Some(record) = s.try_next().await? {
-                    yield record;
-                }
+impl QueryBudget {
+    /// Allows `per_hour` lookups per hour, in bursts of up to a minute's worth.
+    pub fn per_hour(per_hour: u32) -> Self {
+        assert_ne!(per_hour, 0, "query budget must be positive");
+        let burst = f64::from(per_hour / 60).max(1.0);
+        Self {
+            per_sec: f64::from(per_hour) / 3600.0,
+            burst,
+            state: tokio::sync::Mutex::new((burst, Instant::now())),
+        }
+    }
 
-                let mut s = resolve_branch(task_group.clone(),backend.clone(), host.clone(), hashset![ *enr_root ], BranchKind::Enr);
-                while let Some(record) = s.try_next().await? {
-                    yield record;
-                }
+    async fn acquire(&self) {
+        let mut state = self.state.lock().await;
+        let (tokens, refilled) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + (now - *refilled).as_secs_f64() * self.per_sec).min(self.burst);
+        *refilled = now;
+
+        if *tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - *tokens) / self.per_sec);
+            trace!("Query budget spent, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+            *tokens = 1.0;
+            *refilled = Instant::now();
+        }
+        *tokens -= 1.0;
+    }
+}
+
+/// Ends a query once it stops finding nodes, see `Resolver::with_adaptive_stop`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveStop {
+    /// Nodes to yield before the query may be stopped.
+    pub min_nodes: usize,
+    /// Longest wait for the next node.
+    pub idle: Duration,
+    /// Lowest rate of nodes per second, averaged over the last `idle`, to keep going at.
+    pub rate_floor: f64,
+}
+
+/// Why `AdaptiveStop` ended a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Idle,
+    RateFloor,
+}
+
+/// How a single query ended, see `Resolver::query_with_outcome`. Filled in as the query
+/// stream is consumed.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOutcome {
+    adaptive_stop: Arc<Mutex<Option<StopReason>>>,
+}
+
+impl QueryOutcome {
+    /// Why `AdaptiveStop` ended the query, `None` if it did not.
+    pub fn adaptive_stop(&self) -> Option<StopReason> {
+        *self.adaptive_stop.lock().unwrap()
+    }
+}
+
+/// Counters shared by all queries of a resolver.
+#[derive(Debug, Default)]
+pub struct ResolverStats {
+    normalized_enrs: AtomicUsize,
+    duplicates_suppressed: AtomicUsize,
+}
+
+impl ResolverStats {
+    /// ENRs published padded or in the standard base64 alphabet, which had to be normalized
+    /// to parse.
+    pub fn normalized_enrs(&self) -> usize {
+        self.normalized_enrs.load(Ordering::Relaxed)
+    }
+
+    /// ENRs skipped as duplicates under the `DedupMode` of the resolver.
+    pub fn duplicates_suppressed(&self) -> usize {
+        self.duplicates_suppressed.load(Ordering::Relaxed)
+    }
+}
+
+/// Ends `s` according to `stop`, dropping it to cancel its outstanding lookups.
+fn stop_adaptively<T: Send + 'static>(
+    mut s: Pin<Box<dyn Stream<Item = anyhow::Result<T>> + Send + 'static>>,
+    stop: AdaptiveStop,
+    outcome: QueryOutcome,
+) -> Pin<Box<dyn Stream<Item = anyhow::Result<T>> + Send + 'static>> {
+    Box::pin(try_stream! {
+        let started = Instant::now();
+        let mut recent = VecDeque::new();
+        let mut yielded = 0;
+        loop {
+            let record = if yielded < stop.min_nodes {
+                s.try_next().await?
+            } else if let Ok(record) = tokio::time::timeout(stop.idle, s.try_next()).await {
+                record?
             } else {
-                Err(anyhow!("Expected root, got {:?}", record))?
+                debug!("No new nodes in {:?}, stopping", stop.idle);
+                *outcome.adaptive_stop.lock().unwrap() = Some(StopReason::Idle);
+                break;
+            };
+            let record = match record {
+                Some(record) => record,
+                None => break,
+            };
+
+            let now = Instant::now();
+            recent.push_back(now);
+            while recent.front().map_or(false, |t| now - *t > stop.idle) {
+                recent.pop_front();
+            }
+            yielded += 1;
+            yield record;
+
+            let rate = recent.len() as f64 / stop.idle.as_secs_f64();
+            if yielded >= stop.min_nodes && now - started >= stop.idle && rate < stop.rate_floor {
+                debug!("Discovery rate fell to {} nodes per second, stopping", rate);
+                *outcome.adaptive_stop.lock().unwrap() = Some(StopReason::RateFloor);
+                break;
             }
-            trace!("Resolution of tree at {} complete", host);
-        } else {
-            warn!("No records found for tree {}", host);
         }
     })
 }
 
-pub struct Resolver<B: Backend, K: EnrKeyUnambiguous> {
+/// Shape of the ENR subtree of a tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TreeShape {
+    /// Number of branches on the longest path from the subtree root to a leaf.
+    pub depth: usize,
+    pub max_branch_width: usize,
+    pub branch_count: usize,
+    pub leaf_count: usize,
+}
+
+/// Changes made to a node set by `Resolver::sync_into`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncCounts {
+    pub added: usize,
+    /// Nodes whose ENR was replaced with one of a higher sequence number.
+    pub updated: usize,
+}
+
+/// Predicate deciding whether a resolved ENR is yielded.
+pub type EnrFilter<K> = Arc<dyn Fn(&Enr<K>) -> bool + Send + Sync>;
+
+struct QueryContext<B, K: EnrKey> {
+    task_group: Arc<TaskGroup>,
     backend: Arc<B>,
-    task_group: Option<Arc<TaskGroup>>,
-    seen_sequence: Option<usize>,
-    remote_whitelist: Option<Arc<HashMap<String, K::PublicKey>>>,
+    apex_backend: Option<Arc<dyn Backend>>,
+    domain_timeouts: Arc<HashMap<String, Duration>>,
+    record_timeouts: Option<RecordTimeouts>,
+    max_txt_size: usize,
+    scheme: Arc<RecordScheme>,
+    require_public_ip: bool,
+    require_endpoint: bool,
+    required_entries: Arc<Vec<EnrRequirement>>,
+    filters: Arc<Vec<EnrFilter<K>>>,
+    dedup_mode: DedupMode,
+    strict_branches: bool,
+    skip_unsupported_enrs: bool,
+    verify_then_resolve: bool,
+    verify_hashes: bool,
+    follow_inline_links: bool,
+    stats: Arc<ResolverStats>,
+    query_budget: Option<Arc<QueryBudget>>,
+    verbose_enrs: bool,
+    max_records: Option<usize>,
+    parsed_records: AtomicUsize,
+    max_links: Option<usize>,
+    followed_links: AtomicUsize,
+    diagnostics: Option<tokio::sync::mpsc::UnboundedSender<Diagnostic>>,
+    jitter: Option<(Duration, Mutex<StdRng>)>,
+    yielded: Mutex<HashSet<[u8; 32]>>,
+    newest_seq: Mutex<HashMap<NodeId, u64>>,
+    cancelled: AtomicBool,
+    outcome: QueryOutcome,
 }
 
-impl<B: Backend, K: EnrKeyUnambiguous> Resolver<B, K> {
-    pub fn new(backend: Arc<B>) -> Self {
-        Self {
-            backend,
-            task_group: None,
-            seen_sequence: None,
-            remote_whitelist: None,
+impl<B: Backend, K: EnrKey> QueryContext<B, K> {
+    async fn get_root(&self, host: &str) -> anyhow::Result<Option<String>> {
+        self.spend_budget().await;
+        let backend = match &self.apex_backend {
+            Some(apex_backend) => &**apex_backend,
+            None => &*self.backend as &dyn Backend,
+        };
+        let lookup = self.lookup(backend, host, host.to_string());
+        match self.record_timeouts {
+            Some(timeouts) => tokio::time::timeout(timeouts.root, lookup)
+                .await
+                .map_err(|_| anyhow!("Timed out resolving root of {}", host))?,
+            None => lookup.await,
         }
     }
 
-    pub fn with_task_group(&mut self, task_group: Arc<TaskGroup>) -> &mut Self {
-        self.task_group = Some(task_group);
-        self
+    /// Fetches a record below the tree root, along with the time the lookup took.
+    async fn get_child(
+        &self,
+        host: &str,
+        fqdn: String,
+    ) -> anyhow::Result<(Option<String>, Duration)> {
+        self.spend_budget().await;
+        let started = Instant::now();
+        let record = match self.record_timeouts {
+            Some(timeouts) => tokio::time::timeout(
+                timeouts.branch.max(timeouts.leaf),
+                self.get_record(host, fqdn.clone()),
+            )
+            .await
+            .map_err(|_| anyhow!("Timed out resolving {}", fqdn))??,
+            None => self.get_record(host, fqdn).await?,
+        };
+
+        Ok((record, started.elapsed()))
     }
 
-    pub fn with_seen_sequence(&mut self, seen_sequence: usize) -> &mut Self {
-        self.seen_sequence = Some(seen_sequence);
-        self
+    /// Waits for the query budget to allow another lookup, then for any jitter. Done ahead of
+    /// any timeouts, which only cover the lookup itself.
+    async fn spend_budget(&self) {
+        if let Some(query_budget) = &self.query_budget {
+            query_budget.acquire().await;
+        }
+
+        if let Some((jitter, rng)) = &self.jitter {
+            if *jitter > Duration::from_secs(0) {
+                let delay = rng
+                    .lock()
+                    .unwrap()
+                    .gen_range(Duration::from_secs(0)..*jitter);
+                tokio::time::sleep(delay).await;
+            }
+        }
     }
 
-    pub fn with_remote_whitelist(
-        &mut self,
-        remote_whitelist: Arc<HashMap<String, K::PublicKey>>,
-    ) -> &mut Self {
-        self.remote_whitelist = Some(remote_whitelist);
-        self
+    /// Whether a record took longer to arrive than the timeout for its kind allows.
+    fn is_late(&self, kind: RecordKind, elapsed: Duration) -> bool {
+        self.record_timeouts
+            .map_or(false, |timeouts| elapsed > timeouts.for_kind(kind))
     }
 
-    pub fn query(&self, host: impl Display, public_key: Option<K::PublicKey>) -> QueryStream<K> {
-        resolve_tree(
-            self.task_group.clone(),
-            self.backend.clone(),
-            host.to_string(),
-            public_key,
-            self.seen_sequence,
-            self.remote_whitelist.clone(),
-        )
+    async fn get_record(&self, host: &str, fqdn: String) -> anyhow::Result<Option<String>> {
+        self.lookup(&*self.backend, host, fqdn).await
     }
 
-    pub fn query_tree(&self, tree_link: impl AsRef<str>) -> QueryStream<K> {
-        match DnsRecord::<K>::from_str(tree_link.as_ref()).and_then(|link| {
-            if let DnsRecord::Link { public_key, domain } = link {
-                info!("{}/{}", domain, hex::encode(public_key.encode()));
-                Ok((public_key, domain))
-            } else {
-                bail!("Unexpected record type")
+    async fn lookup(
+        &self,
+        backend: &dyn Backend,
+        host: &str,
+        fqdn: String,
+    ) -> anyhow::Result<Option<String>> {
+        let record = if let Some(timeout) = self.domain_timeouts.get(host) {
+            tokio::time::timeout(*timeout, backend.get_record(fqdn.clone()))
+                .await
+                .map_err(|_| anyhow!("Timed out resolving {}", fqdn))??
+        } else {
+            backend.get_record(fqdn.clone()).await?
+        };
+
+        check_txt_size(fqdn, record, self.max_txt_size)
+    }
+
+    fn parse<A: EnrKeyUnambiguous>(
+        &self,
+        text: &str,
+        strict: bool,
+    ) -> anyhow::Result<DnsRecord<K, A>> {
+        let record = DnsRecord::parse(text, strict, &self.scheme)?;
+        self.count_record()?;
+        if let DnsRecord::Enr { .. } = &record {
+            if text
+                .strip_prefix(self.scheme.enr.as_str())
+                .map_or(false, is_sloppy_base64)
+            {
+                self.stats.normalized_enrs.fetch_add(1, Ordering::Relaxed);
             }
-        }) {
-            Ok((public_key, domain)) => self.query(domain, Some(public_key)),
-            Err(e) => Box::pin(tokio_stream::once(Err(e))),
         }
+
+        Ok(record)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use k256::{
-        ecdsa::{SigningKey, VerifyingKey},
-        EncodedPoint,
-    };
-    use maplit::hashmap;
-    use std::collections::{HashMap, HashSet};
-    use tracing_subscriber::EnvFilter;
+    /// Checks an ENR leaf with the given payload like `parse` does, but without decoding it.
+    /// Returns its text with the EIP-1459 prefix and an unpadded base64url payload.
+    fn enr_text(&self, text: &str, payload: &str) -> anyhow::Result<String> {
+        if text.len() > MAX_RECORD_LEN {
+            return Err(RecordTooLong(text.len()).into());
+        }
+        let normalized = check_enr_payload(payload, self.strict_branches)?;
+        self.count_record()?;
+        if is_sloppy_base64(payload) {
+            self.stats.normalized_enrs.fetch_add(1, Ordering::Relaxed);
+        }
 
-    fn test_records_to_hashmap(
-        domain: &str,
-        records: &[(Option<&str>, &str)],
-    ) -> HashMap<String, String> {
-        records
-            .iter()
-            .map(|(sub, entry)| {
-                (
-                    format!(
-                        "{}{}",
-                        sub.map(|s| format!("{}.", s)).unwrap_or_default(),
-                        domain
-                    ),
-                    entry.to_string(),
-                )
-            })
-            .collect()
+        Ok(format!("{}{}", ENR_PREFIX, normalized))
     }
 
-    fn test_records_to_hashmap_geth(records: &[(&str, &str)]) -> HashMap<String, String> {
-        records
+    fn count_record(&self) -> Result<(), TreeTooLarge> {
+        if let Some(limit) = self.max_records {
+            if self.parsed_records.fetch_add(1, Ordering::Relaxed) >= limit {
+                return Err(TreeTooLarge { limit });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts a link about to be followed, returning `false` if `max_links` were already.
+    fn take_link(&self, domain: &str) -> bool {
+        match self.max_links {
+            Some(limit) if self.followed_links.fetch_add(1, Ordering::Relaxed) >= limit => {
+                debug!("Followed {} links already, skipping {}", limit, domain);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Stops all outstanding work of this query after a fatal error.
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Applies the query's filters and deduplication to an ENR found at `label`.
+    fn should_yield(&self, label: &Base32Hash, text: &str, record: &Enr<K>) -> bool {
+        if self.require_public_ip && !has_public_ips(record) {
+            debug!(
+                "Skipping ENR with non-public address: {}",
+                record.to_base64()
+            );
+            return false;
+        }
+
+        if self.require_endpoint && !has_endpoint(record) {
+            debug!("Skipping ENR without endpoint: {}", record.to_base64());
+            return false;
+        }
+
+        if !self.is_wanted(record) {
+            trace!("Skipping unwanted ENR at {}", label);
+            return false;
+        }
+
+        if !self.mark_yielded(text, record) {
+            trace!("Skipping duplicate ENR at {}", label);
+            self.stats
+                .duplicates_suppressed
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        if self.verbose_enrs {
+            debug!(
+                label = %label,
+                node_id = %hex::encode(record.node_id().raw()),
+                ip = ?record.ip(),
+                ip6 = ?record.ip6(),
+                tcp = ?record.tcp(),
+                udp = ?record.udp(),
+                "Resolved ENR"
+            );
+        }
+
+        true
+    }
+
+    fn is_wanted(&self, record: &Enr<K>) -> bool {
+        self.required_entries.iter().all(|r| r.matches(record))
+            && self.filters.iter().all(|f| f(record))
+    }
+
+    /// Records that an ENR is about to be yielded, returning `false` if the dedup mode
+    /// suppresses it.
+    fn mark_yielded(&self, text: &str, record: &Enr<K>) -> bool {
+        match self.dedup_mode {
+            DedupMode::Off => true,
+            DedupMode::ExactDuplicatesOnly => {
+                self.yielded.lock().unwrap().insert(content_hash(text))
+            }
+            DedupMode::ByNodeIdKeepNewest => {
+                let mut newest_seq = self.newest_seq.lock().unwrap();
+                match newest_seq.get(&record.node_id()) {
+                    Some(seq) if *seq >= record.seq() => false,
+                    _ => {
+                        newest_seq.insert(record.node_id(), record.seq());
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// IPv4 blocks of the IANA special-purpose address registry that are not globally reachable,
+/// and the reserved block including the limited broadcast address.
+const SPECIAL_PURPOSE_V4: &[(Ipv4Addr, u8)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8),
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(100, 64, 0, 0), 10),
+    (Ipv4Addr::new(127, 0, 0, 0), 8),
+    (Ipv4Addr::new(169, 254, 0, 0), 16),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 0, 0, 0), 24),
+    (Ipv4Addr::new(192, 0, 2, 0), 24),
+    (Ipv4Addr::new(192, 88, 99, 0), 24),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+    (Ipv4Addr::new(198, 18, 0, 0), 15),
+    (Ipv4Addr::new(198, 51, 100, 0), 24),
+    (Ipv4Addr::new(203, 0, 113, 0), 24),
+    (Ipv4Addr::new(240, 0, 0, 0), 4),
+];
+
+/// IPv6 blocks of the IANA special-purpose address registry that are not globally reachable.
+const SPECIAL_PURPOSE_V6: &[(Ipv6Addr, u8)] = &[
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 128),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 128),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96),
+    (Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0), 48),
+    (Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0), 64),
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 23),
+    (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32),
+    (Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16),
+    (Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 0), 20),
+    (Ipv6Addr::new(0x5f00, 0, 0, 0, 0, 0, 0, 0), 16),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+];
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !SPECIAL_PURPOSE_V4
             .iter()
-            .map(|(domain, entry)| (domain.to_string(), entry.to_string()))
-            .collect()
+            .any(|(net, len)| u32::from(ip) >> (32 - len) == u32::from(*net) >> (32 - len)),
+        IpAddr::V6(ip) => !SPECIAL_PURPOSE_V6.iter().any(|(net, len)| {
+            u128::from(ip)
+                .checked_shr(u32::from(128 - len))
+                .unwrap_or(0)
+                == u128::from(*net)
+                    .checked_shr(u32::from(128 - len))
+                    .unwrap_or(0)
+        }),
     }
+}
 
-    #[tokio::test]
-    async fn eip_example() {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .try_init();
+fn has_public_ips<K: EnrKey>(record: &Enr<K>) -> bool {
+    record.ip().map_or(true, |ip| is_public_ip(ip.into()))
+        && record.ip6().map_or(true, |ip| is_public_ip(ip.into()))
+}
 
-        const DOMAIN: &str = "mynodes.org";
-        const TEST_RECORDS: &[(Option<&str>, &str)] = &[
-            (
-                None,
-                "enrtree-root:v1 e=JWXYDBPXYWG6FX3GMDIBFA6CJ4 l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=1 sig=o908WmNp7LibOfPsr4btQwatZJ5URBr2ZAuxvK4UWHlsB9sUOTJQaGAlLPVAhM__XJesCHxLISo94z5Z2a463gA"
-            ), (
-                Some("C7HRFPF3BLGF3YR4DY5KX3SMBE"),
-                "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"
-            ), (
-                Some("JWXYDBPXYWG6FX3GMDIBFA6CJ4"),
-                "enrtree-branch:2XS2367YHAXJFGLZHVAWLQD4ZY,H4FHT4B454P6UXFD7JCYQ5PWDY,MHTDO6TMUBRIA2XWG5LUDACK24",
-            ), (
-                Some("2XS2367YHAXJFGLZHVAWLQD4ZY"),
-                "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA"
-            ), (
-                Some("H4FHT4B454P6UXFD7JCYQ5PWDY"),
-                "enr:-HW4QAggRauloj2SDLtIHN1XBkvhFZ1vtf1raYQp9TBW2RD5EEawDzbtSmlXUfnaHcvwOizhVYLtr7e6vw7NAf6mTuoCgmlkgnY0iXNlY3AyNTZrMaECjrXI8TLNXU0f8cthpAMxEshUyQlK-AM0PW2wfrnacNI"
-            ), (
-                Some("MHTDO6TMUBRIA2XWG5LUDACK24"),
-                "enr:-HW4QLAYqmrwllBEnzWWs7I5Ev2IAs7x_dZlbYdRdMUx5EyKHDXp7AV5CkuPGUPdvbv1_Ms1CPfhcGCvSElSosZmyoqAgmlkgnY0iXNlY3AyNTZrMaECriawHKWdDRk2xeZkrOXBQ0dfMFLHY4eENZwdufn1S1o"
-            )
-        ];
+fn has_endpoint<K: EnrKey>(record: &Enr<K>) -> bool {
+    !socket_addrs(record).is_empty()
+}
 
-        let data = test_records_to_hashmap(DOMAIN, TEST_RECORDS);
+/// Distinct IPv4 and IPv6 TCP and UDP endpoints of an ENR, skipping unspecified addresses and
+/// port 0.
+fn socket_addrs<K: EnrKey>(record: &Enr<K>) -> Vec<SocketAddr> {
+    let endpoints = [
+        (record.ip().map(IpAddr::from), [record.tcp(), record.udp()]),
+        (
+            record.ip6().map(IpAddr::from),
+            [record.tcp6(), record.udp6()],
+        ),
+    ];
 
-        let mut s = Resolver::<_, SigningKey>::new(Arc::new(data))
-            .with_remote_whitelist(Arc::new(hashmap!{
-                "morenodes.example.org".to_string() => VerifyingKey::from_encoded_point(&EncodedPoint::from_bytes(&hex::decode("049f88229042fef9200246f49f94d9b77c4e954721442714e85850cb6d9e5daf2d880ea0e53cb3ac1a75f9923c2726a4f941f7d326781baa6380754a360de5c2b6").unwrap()).unwrap()).unwrap()
-            }))
-            .query(DOMAIN.to_string(), None);
-        let mut out = HashSet::new();
-        while let Some(record) = s.try_next().await.unwrap() {
-            assert!(out.insert(record.to_base64()));
+    let mut addrs = vec![];
+    for (ip, ports) in &endpoints {
+        if let Some(ip) = ip.filter(|ip| !ip.is_unspecified()) {
+            for port in ports.iter().flatten().filter(|port| **port != 0) {
+                let addr = SocketAddr::new(ip, *port);
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
         }
-        assert_eq!(
-            out,
-            hashset![
-                "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA",
-                "enr:-HW4QAggRauloj2SDLtIHN1XBkvhFZ1vtf1raYQp9TBW2RD5EEawDzbtSmlXUfnaHcvwOizhVYLtr7e6vw7NAf6mTuoCgmlkgnY0iXNlY3AyNTZrMaECjrXI8TLNXU0f8cthpAMxEshUyQlK-AM0PW2wfrnacNI",
-                "enr:-HW4QLAYqmrwllBEnzWWs7I5Ev2IAs7x_dZlbYdRdMUx5EyKHDXp7AV5CkuPGUPdvbv1_Ms1CPfhcGCvSElSosZmyoqAgmlkgnY0iXNlY3AyNTZrMaECriawHKWdDRk2xeZkrOXBQ0dfMFLHY4eENZwdufn1S1o",
-            ].into_iter().map(ToString::to_string).collect()
-        );
     }
+    addrs
+}
 
-    #[tokio::test]
-    async fn bad_node() {
-        const TEST_RECORDS: &[(&str, &str)] = &[
-            ("n",                            "enrtree-root:v1 e=INDMVBZEEQ4ESVYAKGIYU74EAA l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=3 sig=Vl3AmunLur0JZ3sIyJPSH6A3Vvdp4F40jWQeCmkIhmcgwE4VC5U9wpK8C_uL_CMY29fd6FAhspRvq2z_VysTLAA"),
-		    ("C7HRFPF3BLGF3YR4DY5KX3SMBE.n", "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"),
-		    ("INDMVBZEEQ4ESVYAKGIYU74EAA.n", "enr:-----"),
-        ];
+/// What a tree walk yields for its ENR leaves: decoded records, or their text for
+/// `Resolver::query_raw`.
+trait Leaf<K: EnrKey>: Send + Sized + 'static {
+    /// Builds the leaf for the ENR at `label`, given its text as returned by
+    /// `QueryContext::enr_text`. `None` skips it.
+    fn from_text<B: Backend>(
+        ctx: &QueryContext<B, K>,
+        label: &Base32Hash,
+        text: String,
+    ) -> anyhow::Result<Option<Self>>;
 
-        let data = test_records_to_hashmap_geth(TEST_RECORDS);
+    /// Like `from_text`, for an ENR that was decoded while verifying its subtree.
+    fn from_decoded<B: Backend>(
+        ctx: &QueryContext<B, K>,
+        label: &Base32Hash,
+        text: &str,
+        record: Enr<K>,
+    ) -> Option<Self>;
+
+    /// Builds the leaf for a fallback ENR, which is yielded as is.
+    fn from_fallback(record: Enr<K>) -> Self;
+}
+
+impl<K: EnrKey> Leaf<K> for Enr<K> {
+    fn from_text<B: Backend>(
+        ctx: &QueryContext<B, K>,
+        label: &Base32Hash,
+        text: String,
+    ) -> anyhow::Result<Option<Self>> {
+        let record = match parse_enr(&text) {
+            Err(e @ InvalidEnr::UnsupportedScheme(_)) if ctx.skip_unsupported_enrs => {
+                debug!("Skipping ENR at {}: {}", label, e);
+                return Ok(None);
+            }
+            res => res?,
+        };
+
+        Ok(Self::from_decoded(ctx, label, &text, record))
+    }
+
+    fn from_decoded<B: Backend>(
+        ctx: &QueryContext<B, K>,
+        label: &Base32Hash,
+        text: &str,
+        record: Enr<K>,
+    ) -> Option<Self> {
+        if ctx.should_yield(label, text, &record) {
+            Some(record)
+        } else {
+            None
+        }
+    }
+
+    fn from_fallback(record: Enr<K>) -> Self {
+        record
+    }
+}
+
+/// ENR text is yielded without filtering or deduplication, which need decoded records.
+impl<K: EnrKey> Leaf<K> for String {
+    fn from_text<B: Backend>(
+        _: &QueryContext<B, K>,
+        _: &Base32Hash,
+        text: String,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(Some(text))
+    }
+
+    fn from_decoded<B: Backend>(
+        _: &QueryContext<B, K>,
+        _: &Base32Hash,
+        _: &str,
+        record: Enr<K>,
+    ) -> Option<Self> {
+        Some(record.to_base64())
+    }
+
+    fn from_fallback(record: Enr<K>) -> Self {
+        record.to_base64()
+    }
+}
+
+/// ENR leaves paired with the tree that published them.
+type SourcedStream<L> = Pin<Box<dyn Stream<Item = anyhow::Result<(Origin, L)>> + Send + 'static>>;
+type RecordSender<L> = tokio::sync::mpsc::Sender<anyhow::Result<(Origin, L)>>;
+
+fn without_origin<K: EnrKey>(s: OriginQueryStream<K>) -> QueryStream<K> {
+    Box::pin(s.map(|record| record.map(|(record, _)| record)))
+}
+
+/// Forwards records from a subtree to the parent channel.
+///
+/// Stops pulling from the subtree as soon as the parent receiver is gone, dropping it so that
+/// its own tasks notice and stop as well.
+async fn forward<L>(mut s: SourcedStream<L>, tx: &RecordSender<L>) -> anyhow::Result<()> {
+    loop {
+        let item = tokio::select! {
+            _ = tx.closed() => None,
+            item = s.try_next() => item?,
+        };
+
+        match item {
+            Some(item) => {
+                if tx.send(Ok(item)).await.is_err() {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+fn resolve_branch<B: Backend, K: EnrKey, A: EnrKeyUnambiguous, L: Leaf<K>>(
+    ctx: Arc<QueryContext<B, K>>,
+    host: String,
+    sequence: usize,
+    parent: Option<Base32Hash>,
+    children: Vec<Base32Hash>,
+    kind: BranchKind<A::PublicKey>,
+    tree_key: Option<A::PublicKey>,
+) -> SourcedStream<L> {
+    let (tx, mut branches_res) = tokio::sync::mpsc::channel(1);
+    let task_group = ctx.task_group.clone();
+    for subdomain in &children {
+        let fqdn = format!("{}.{}", subdomain, host);
+        task_group.spawn_with_name(format!("DNS discovery: {}", fqdn), {
+            let subdomain = *subdomain;
+            let tx = tx.clone();
+            let ctx = ctx.clone();
+            let host = host.clone();
+            let kind = kind.clone();
+            let tree_key = tree_key.clone();
+            let fqdn = fqdn.clone();
+            async move {
+                if let Err(e) = {
+                    let tx = tx.clone();
+                    let ctx = ctx.clone();
+                    let fqdn = fqdn.clone();
+                    async move {
+                        if ctx.is_cancelled() {
+                            trace!("Query cancelled, not resolving {}", fqdn);
+                            return Ok(());
+                        }
+
+                        if tx.is_closed() {
+                            trace!("Consumer gone, not resolving {}", fqdn);
+                            return Ok(());
+                        }
+
+                        let (record, elapsed) = ctx.get_child(&host, fqdn.clone()).await?;
+                        if tx.is_closed() {
+                            trace!("Consumer gone, dropping {}", subdomain);
+                            return Ok(());
+                        }
+
+                        if let Some(text) = record {
+                            trace!("Resolved record {}: {:?}", subdomain, text);
+                            if ctx.verify_hashes {
+                                raw::verify_hash(&fqdn, &subdomain, &text)?;
+                            }
+                            // ENR leaves are handed to `L` undecoded, so that `query_raw`
+                            // never decodes them.
+                            if let Some(payload) = ctx.scheme.enr_payload(&text) {
+                                let text = ctx.enr_text(&text, payload)?;
+                                if ctx.is_late(RecordKind::Enr, elapsed) {
+                                    debug!(
+                                        "Skipping {} record at {} that took {:?}",
+                                        RecordKind::Enr,
+                                        subdomain,
+                                        elapsed
+                                    );
+                                    return Ok(());
+                                }
+                                raw::check_kind(
+                                    &fqdn,
+                                    subdomain,
+                                    parent,
+                                    RecordKind::Enr,
+                                    kind.leaf_kind(),
+                                )?;
+                                if let Some(leaf) = L::from_text(&ctx, &subdomain, text)? {
+                                    let origin = Origin {
+                                        domain: host,
+                                        sequence: Some(sequence),
+                                    };
+                                    if tx.send(Ok((origin, leaf))).await.is_err() {
+                                        trace!("Consumer gone, dropping {}", subdomain);
+                                    }
+                                }
+
+                                return Ok(());
+                            }
+                            let record = ctx.parse::<A>(&text, ctx.strict_branches)?;
+                            if ctx.is_late(record.kind(), elapsed) {
+                                debug!(
+                                    "Skipping {} record at {} that took {:?}",
+                                    record.kind(),
+                                    subdomain,
+                                    elapsed
+                                );
+                                return Ok(());
+                            }
+                            let link_whitelist = kind.link_whitelist(ctx.follow_inline_links);
+                            // Links among the ENRs are allowed when inline links are followed.
+                            if !(record.kind() == RecordKind::Link && link_whitelist.is_some()) {
+                                raw::check_kind(
+                                    &fqdn,
+                                    subdomain,
+                                    parent,
+                                    record.kind(),
+                                    kind.leaf_kind(),
+                                )?;
+                            }
+                            if let Some(children) = raw::expand_branch(&record) {
+                                let s = resolve_branch::<_, _, A, L>(
+                                    ctx,
+                                    host,
+                                    sequence,
+                                    Some(subdomain),
+                                    children,
+                                    kind.clone(),
+                                    tree_key,
+                                );
+                                return forward(s, &tx).await;
+                            }
+                            if let (
+                                DnsRecord::Link { public_key, domain },
+                                Some(remote_whitelist),
+                            ) = (record, link_whitelist)
+                            {
+                                if is_self_link(&host, tree_key.as_ref(), &domain, &public_key) {
+                                    warn!("Skipping link of {} to itself", host);
+                                } else if !domain_is_allowed(remote_whitelist, &domain, &public_key)
+                                {
+                                    trace!("Skipping subtree for forbidden domain: {}", domain);
+                                } else if ctx.take_link(&domain) {
+                                    forward(
+                                        resolve_tree::<_, _, A, L>(
+                                            ctx,
+                                            domain,
+                                            Some(public_key),
+                                            None,
+                                            remote_whitelist.clone(),
+                                            Default::default(),
+                                        ),
+                                        &tx,
+                                    )
+                                    .await?;
+                                }
+                            }
+                        } else {
+                            warn!("Child {} is empty", subdomain);
+                        }
+
+                        Ok(())
+                    }
+                }
+                .await
+                {
+                    if let Some(diagnostics) = &ctx.diagnostics {
+                        debug!("Skipping {}: {}", fqdn, e);
+                        let _ = diagnostics.send(Diagnostic { fqdn, error: e });
+                    } else {
+                        ctx.cancel();
+                        let _ = tx.send(Err(e)).await;
+                    }
+                }
+            }
+            .instrument(Span::current())
+        });
+    }
+
+    Box::pin(stream! {
+        trace!("Resolving branch {:?}", children);
+        while let Some(v) = branches_res.recv().await {
+            yield v;
+        }
+        trace!("Branch {:?} resolution complete", children);
+    })
+}
+
+/// Fetches every record below `label`, checking each one against the label it is published
+/// under. Returns the leaves, which must all be of `leaf_kind`.
+async fn fetch_verified_subtree<B: Backend, K: EnrKey, A: EnrKeyUnambiguous>(
+    ctx: &QueryContext<B, K>,
+    host: &str,
+    label: Base32Hash,
+    leaf_kind: RecordKind,
+) -> anyhow::Result<Vec<raw::VerifiedRecord<K, A>>> {
+    let mut leaves = vec![];
+    let mut pending = vec![(None, label)];
+    while let Some((parent, label)) = pending.pop() {
+        if ctx.is_cancelled() {
+            bail!("Query cancelled");
+        }
+
+        let fqdn = format!("{}.{}", label, host);
+        let (text, elapsed) = match ctx.get_child(host, fqdn.clone()).await? {
+            (Some(text), elapsed) => (text, elapsed),
+            (None, _) => {
+                warn!("Child {} is empty", label);
+                continue;
+            }
+        };
+
+        let record = raw::verify_record(fqdn, label, text, |text| {
+            ctx.parse(text, ctx.strict_branches)
+        })?;
+        let kind = record.record.kind();
+        if ctx.is_late(kind, elapsed) {
+            debug!(
+                "Skipping {} record at {} that took {:?}",
+                kind, label, elapsed
+            );
+            continue;
+        }
+        // Links among the ENRs are leaves too when inline links are followed.
+        if !(ctx.follow_inline_links && leaf_kind == RecordKind::Enr && kind == RecordKind::Link) {
+            raw::expect_kind(&record, parent, leaf_kind)?;
+        }
+        match raw::expand_branch(&record.record) {
+            Some(children) => {
+                pending.extend(children.into_iter().map(|child| (Some(label), child)));
+            }
+            None => leaves.push(record),
+        }
+    }
+
+    Ok(leaves)
+}
+
+fn resolve_tree<B: Backend, K: EnrKey, A: EnrKeyUnambiguous, L: Leaf<K>>(
+    ctx: Arc<QueryContext<B, K>>,
+    host: String,
+    public_key: Option<A::PublicKey>,
+    seen_sequence: Option<usize>,
+    remote_whitelist: Option<Arc<HashMap<String, A::PublicKey>>>,
+    fallback: Arc<Vec<Enr<K>>>,
+) -> SourcedStream<L> {
+    Box::pin(try_stream! {
+        if ctx.is_cancelled() {
+            trace!("Query cancelled, not resolving tree {}", host);
+            return;
+        }
+
+        let record = match ctx.get_root(&host).await {
+            Err(e) if !fallback.is_empty() => {
+                warn!("Failed to resolve root of {}, yielding {} fallback ENRs: {}", host, fallback.len(), e);
+                for record in fallback.iter() {
+                    yield (Origin { domain: host.clone(), sequence: None }, L::from_fallback(record.clone()));
+                }
+                return;
+            }
+            res => res?,
+        };
+        if let Some(record) = &record {
+            let record = raw::check_root(ctx.parse::<A>(record, false)?, public_key.as_ref())?;
+            let UnsignedRoot { enr_root, link_root, sequence, .. } = &record.base;
+
+            if let Some(seen) = seen_sequence {
+                if *sequence <= seen {
+                    // We have already seen this record.
+                    return;
+                }
+            }
+
+            if ctx.verify_then_resolve {
+                let links = fetch_verified_subtree::<_, _, A>(&ctx, &host, *link_root, RecordKind::Link).await?;
+                let enrs = fetch_verified_subtree::<_, _, A>(&ctx, &host, *enr_root, RecordKind::Enr).await?;
+                trace!("Verified tree at {}", host);
+
+                let (inline_links, enrs): (Vec<_>, Vec<_>) = enrs
+                    .into_iter()
+                    .partition(|leaf| leaf.record.kind() == RecordKind::Link);
+                for leaf in links.into_iter().chain(inline_links) {
+                    if let DnsRecord::Link { public_key: link_key, domain } = leaf.record {
+                        if is_self_link(&host, public_key.as_ref(), &domain, &link_key) {
+                            warn!("Skipping link of {} to itself", host);
+                        } else if !domain_is_allowed(&remote_whitelist, &domain, &link_key) {
+                            trace!("Skipping subtree for forbidden domain: {}", domain);
+                        } else if ctx.take_link(&domain) {
+                            let mut s = resolve_tree::<_, _, A, L>(ctx.clone(), domain, Some(link_key), None, remote_whitelist.clone(), Default::default());
+                            while let Some(record) = s.try_next().await? {
+                                yield record;
+                            }
+                        }
+                    }
+                }
+
+                for leaf in enrs {
+                    if let DnsRecord::Enr { record } = leaf.record {
+                        if let Some(record) = L::from_decoded(&ctx, &leaf.label, &leaf.text, record) {
+                            yield (Origin { domain: host.clone(), sequence: Some(*sequence) }, record);
+                        }
+                    }
+                }
+            } else {
+                let mut s = resolve_branch::<_, _, A, L>(ctx.clone(), host.clone(), *sequence, None, vec![*link_root], BranchKind::Link { remote_whitelist: remote_whitelist.clone() }, public_key.clone());
+                while let Some(record) = s.try_next().await? {
+                    yield record;
+                }
+
+                let mut s = resolve_branch::<_, _, A, L>(ctx.clone(), host.clone(), *sequence, None, vec![*enr_root], BranchKind::Enr { remote_whitelist }, public_key);
+                while let Some(record) = s.try_next().await? {
+                    yield record;
+                }
+            }
+            trace!("Resolution of tree at {} complete", host);
+        } else {
+            warn!("No records found for tree {}", host);
+        }
+    })
+}
+
+/// Resolves ENR trees. Leaf ENRs are decoded with `K`, while links and root signatures use `A`.
+/// Setting `K` to `CombinedKey` and `A` to a secp256k1 key resolves trees mixing identity
+/// schemes.
+///
+/// With the `ed25519` feature, `A` may also be an ed25519 keypair, for private networks that do
+/// without secp256k1. Such trees are not valid under EIP-1459, which requires secp256k1 keys
+/// for links and roots, and other clients will not resolve them.
+pub struct Resolver<B: Backend, K: EnrKey, A: EnrKeyUnambiguous = K> {
+    backend: Arc<B>,
+    apex_backend: Option<Arc<dyn Backend>>,
+    task_group: Option<Arc<TaskGroup>>,
+    seen_sequence: Option<usize>,
+    remote_whitelist: Option<Arc<HashMap<String, A::PublicKey>>>,
+    domain_timeouts: Arc<HashMap<String, Duration>>,
+    emit_interval: Option<Duration>,
+    record_timeouts: Option<RecordTimeouts>,
+    max_txt_size: usize,
+    scheme: Arc<RecordScheme>,
+    require_public_ip: bool,
+    require_endpoint: bool,
+    required_entries: Arc<Vec<EnrRequirement>>,
+    filters: Arc<Vec<EnrFilter<K>>>,
+    dedup_mode: DedupMode,
+    strict_branches: bool,
+    skip_unsupported_enrs: bool,
+    verify_then_resolve: bool,
+    verify_hashes: bool,
+    follow_inline_links: bool,
+    stats: Arc<ResolverStats>,
+    query_budget: Option<Arc<QueryBudget>>,
+    verbose_enrs: bool,
+    max_records: Option<usize>,
+    max_links: Option<usize>,
+    jitter: Option<Duration>,
+    jitter_seed: Option<u64>,
+    adaptive_stop: Option<AdaptiveStop>,
+    fallback: Arc<Vec<Enr<K>>>,
+    sorted_output: bool,
+    query_ids: Option<AtomicU64>,
+}
+
+impl<B: Backend, K: EnrKey, A: EnrKeyUnambiguous> Resolver<B, K, A> {
+    pub fn new(backend: Arc<B>) -> Self {
+        Self {
+            backend,
+            apex_backend: None,
+            task_group: None,
+            seen_sequence: None,
+            remote_whitelist: None,
+            domain_timeouts: Default::default(),
+            emit_interval: None,
+            record_timeouts: None,
+            max_txt_size: DEFAULT_MAX_TXT_SIZE,
+            scheme: Default::default(),
+            require_public_ip: false,
+            require_endpoint: false,
+            required_entries: Default::default(),
+            filters: Default::default(),
+            dedup_mode: Default::default(),
+            strict_branches: false,
+            skip_unsupported_enrs: false,
+            verify_then_resolve: false,
+            verify_hashes: false,
+            follow_inline_links: false,
+            stats: Default::default(),
+            query_budget: None,
+            verbose_enrs: false,
+            max_records: None,
+            max_links: None,
+            jitter: None,
+            jitter_seed: None,
+            adaptive_stop: None,
+            fallback: Default::default(),
+            sorted_output: false,
+            query_ids: None,
+        }
+    }
+
+    pub fn with_task_group(&mut self, task_group: Arc<TaskGroup>) -> &mut Self {
+        self.task_group = Some(task_group);
+        self
+    }
+
+    /// Routes root record lookups at tree apexes to a separate backend, for split-horizon
+    /// setups where roots and the rest of the tree are served from different DNS views.
+    pub fn with_apex_backend(&mut self, apex_backend: Arc<dyn Backend>) -> &mut Self {
+        self.apex_backend = Some(apex_backend);
+        self
+    }
+
+    pub fn with_seen_sequence(&mut self, seen_sequence: usize) -> &mut Self {
+        self.seen_sequence = Some(seen_sequence);
+        self
+    }
+
+    pub fn with_remote_whitelist(
+        &mut self,
+        remote_whitelist: Arc<HashMap<String, A::PublicKey>>,
+    ) -> &mut Self {
+        self.remote_whitelist = Some(remote_whitelist);
+        self
+    }
+
+    /// Bounds the time spent on each lookup for the given tree domains.
+    pub fn with_per_domain_timeouts(
+        &mut self,
+        domain_timeouts: HashMap<String, Duration>,
+    ) -> &mut Self {
+        self.domain_timeouts = Arc::new(domain_timeouts);
+        self
+    }
+
+    /// Bounds the time spent on each lookup by the kind of record fetched. Applies on top of
+    /// any per-domain timeouts.
+    pub fn with_record_timeouts(&mut self, record_timeouts: RecordTimeouts) -> &mut Self {
+        self.record_timeouts = Some(record_timeouts);
+        self
+    }
+
+    /// Rejects TXT records longer than `max_txt_size` bytes with `TxtTooLarge`, before parsing
+    /// them. Records split over several strings are measured once joined. Records longer than
+    /// `MAX_RECORD_LEN` fail to parse regardless.
+    pub fn with_max_txt_size(&mut self, max_txt_size: usize) -> &mut Self {
+        self.max_txt_size = max_txt_size;
+        self
+    }
+
+    /// Yields at most `per_second` records per second. This paces emission, not lookups:
+    /// resolving tasks block on the bounded internal channel instead of buffering records.
+    pub fn with_emit_rate(&mut self, per_second: u32) -> &mut Self {
+        assert_ne!(per_second, 0, "emit rate must be positive");
+        self.emit_interval = Some(Duration::from_secs(1) / per_second);
+        self
+    }
+
+    /// Resolves trees published with the prefixes of `scheme` instead of those of EIP-1459.
+    pub fn with_scheme(&mut self, scheme: RecordScheme) -> &mut Self {
+        self.scheme = Arc::new(scheme);
+        self
+    }
+
+    /// Skips ENRs advertising addresses that are not globally reachable, per the IANA
+    /// special-purpose address registries.
+    pub fn with_require_public_ip(&mut self, require_public_ip: bool) -> &mut Self {
+        self.require_public_ip = require_public_ip;
+        self
+    }
+
+    /// Skips ENRs that advertise no IPv4 or IPv6 address with a TCP or UDP port to dial.
+    pub fn with_require_endpoint(&mut self, require_endpoint: bool) -> &mut Self {
+        self.require_endpoint = require_endpoint;
+        self
+    }
+
+    /// Only yields ENRs satisfying all of the given requirements.
+    pub fn with_required_entries(&mut self, required_entries: Vec<EnrRequirement>) -> &mut Self {
+        self.required_entries = Arc::new(required_entries);
+        self
+    }
+
+    /// Adds a filter that ENRs must pass to be yielded. All added filters must pass.
+    pub fn with_filter(
+        &mut self,
+        filter: impl Fn(&Enr<K>) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        Arc::make_mut(&mut self.filters).push(Arc::new(filter));
+        self
+    }
+
+    pub fn with_dedup_mode(&mut self, dedup_mode: DedupMode) -> &mut Self {
+        self.dedup_mode = dedup_mode;
+        self
+    }
+
+    /// Fails the query on branches with empty children instead of skipping them, and on ENRs
+    /// that are padded or use the standard base64 alphabet instead of normalizing them.
+    pub fn with_strict_branches(&mut self, strict_branches: bool) -> &mut Self {
+        self.strict_branches = strict_branches;
+        self
+    }
+
+    /// Skips ENRs with an identity scheme `K` does not support instead of failing the query.
+    pub fn with_skip_unsupported_enrs(&mut self, skip_unsupported_enrs: bool) -> &mut Self {
+        self.skip_unsupported_enrs = skip_unsupported_enrs;
+        self
+    }
+
+    /// Fetches each tree in full and checks every record against its hash before yielding
+    /// any of its ENRs, so a tree is either trusted entirely or not at all. Trees are
+    /// walked one lookup at a time in this mode.
+    pub fn with_verify_then_resolve(&mut self, verify_then_resolve: bool) -> &mut Self {
+        self.verify_then_resolve = verify_then_resolve;
+        self
+    }
+
+    /// Checks every subtree record against the hash label it is published under.
+    pub fn with_verify_hashes(&mut self, verify_hashes: bool) -> &mut Self {
+        self.verify_hashes = verify_hashes;
+        self
+    }
+
+    /// Also follows links found in the ENR subtree, treating the linked trees as additional
+    /// sources of ENRs. EIP-1459 only allows links in the link subtree.
+    pub fn with_follow_inline_links(&mut self, follow_inline_links: bool) -> &mut Self {
+        self.follow_inline_links = follow_inline_links;
+        self
+    }
+
+    /// Paces the lookups of all queries made through this resolver to fit `query_budget`.
+    /// The budget may also be shared with other resolvers.
+    pub fn with_query_budget(&mut self, query_budget: Arc<QueryBudget>) -> &mut Self {
+        self.query_budget = Some(query_budget);
+        self
+    }
+
+    /// Fails a query with `TreeTooLarge` once it has parsed more than `max_records` records of
+    /// any kind, including those of linked trees, and cancels its outstanding lookups. There
+    /// is no limit by default; for untrusted trees, a few times the expected number of records
+    /// is a sensible choice, e.g. 10000 for a tree of a few thousand nodes.
+    pub fn with_max_records(&mut self, max_records: usize) -> &mut Self {
+        self.max_records = Some(max_records);
+        self
+    }
+
+    /// Follows at most `max_links` links to other trees over a whole query, at any depth.
+    /// Further links are skipped.
+    pub fn with_max_links(&mut self, max_links: usize) -> &mut Self {
+        self.max_links = Some(max_links);
+        self
+    }
+
+    /// Delays the start of each lookup by a random amount below `jitter`, so that lookups
+    /// launched together are spread out. The delay follows any wait for the query budget.
+    pub fn with_jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Seeds the random delays of `with_jitter`, making them the same for every query.
+    pub fn with_jitter_seed(&mut self, seed: u64) -> &mut Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// Numbers the `query` spans of this resolver from `first_id` instead of picking random
+    /// ids, so that traces are reproducible.
+    pub fn with_deterministic_ids(&mut self, first_id: u64) -> &mut Self {
+        self.query_ids = Some(AtomicU64::new(first_id));
+        self
+    }
+
+    /// Ends queries early once at least `min_nodes` nodes were yielded and either no node
+    /// arrives for `idle`, or the discovery rate falls below `rate_floor`. The reason is
+    /// reported by `query_with_outcome`.
+    pub fn with_adaptive_stop(&mut self, adaptive_stop: AdaptiveStop) -> &mut Self {
+        self.adaptive_stop = Some(adaptive_stop);
+        self
+    }
+
+    /// Yields `enrs` instead of failing when the root of the queried tree cannot be fetched,
+    /// so clients can still bootstrap with DNS down. Linked trees get no fallback, and the
+    /// fallback ENRs are not filtered.
+    pub fn with_fallback(&mut self, enrs: Vec<Enr<K>>) -> &mut Self {
+        self.fallback = Arc::new(enrs);
+        self
+    }
+
+    /// Holds back the ENRs of a query until the whole tree is resolved, then yields them by the
+    /// domain of their tree and by their label, so that the order does not depend on the
+    /// timing of lookups. Labels are derived from the ENRs, and only match the published
+    /// ones for trees that are hashed correctly. Meant for tests.
+    pub fn with_sorted_output(&mut self, sorted_output: bool) -> &mut Self {
+        self.sorted_output = sorted_output;
+        self
+    }
+
+    /// Logs the node id, addresses and ports of every yielded ENR as a structured debug event.
+    pub fn with_verbose_enrs(&mut self, verbose_enrs: bool) -> &mut Self {
+        self.verbose_enrs = verbose_enrs;
+        self
+    }
+
+    pub fn stats(&self) -> &ResolverStats {
+        &self.stats
+    }
+
+    fn context(&self) -> Arc<QueryContext<B, K>> {
+        Arc::new(self.context_with_diagnostics(None))
+    }
+
+    fn context_with_diagnostics(
+        &self,
+        diagnostics: Option<tokio::sync::mpsc::UnboundedSender<Diagnostic>>,
+    ) -> QueryContext<B, K> {
+        QueryContext {
+            task_group: self.task_group.clone().unwrap_or_default(),
+            backend: self.backend.clone(),
+            apex_backend: self.apex_backend.clone(),
+            domain_timeouts: self.domain_timeouts.clone(),
+            record_timeouts: self.record_timeouts,
+            max_txt_size: self.max_txt_size,
+            scheme: self.scheme.clone(),
+            require_public_ip: self.require_public_ip,
+            require_endpoint: self.require_endpoint,
+            required_entries: self.required_entries.clone(),
+            filters: self.filters.clone(),
+            dedup_mode: self.dedup_mode,
+            strict_branches: self.strict_branches,
+            skip_unsupported_enrs: self.skip_unsupported_enrs,
+            verify_then_resolve: self.verify_then_resolve,
+            verify_hashes: self.verify_hashes,
+            follow_inline_links: self.follow_inline_links,
+            stats: self.stats.clone(),
+            query_budget: self.query_budget.clone(),
+            verbose_enrs: self.verbose_enrs,
+            max_records: self.max_records,
+            parsed_records: AtomicUsize::new(0),
+            max_links: self.max_links,
+            followed_links: AtomicUsize::new(0),
+            diagnostics,
+            jitter: self.jitter.map(|jitter| {
+                let rng = match self.jitter_seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                };
+                (jitter, Mutex::new(rng))
+            }),
+            yielded: Default::default(),
+            newest_seq: Default::default(),
+            cancelled: AtomicBool::new(false),
+            outcome: Default::default(),
+        }
+    }
+
+    fn query_sourced<L: Leaf<K>>(
+        &self,
+        ctx: Arc<QueryContext<B, K>>,
+        host: String,
+        public_key: Option<A::PublicKey>,
+    ) -> SourcedStream<L> {
+        resolve_tree::<_, _, A, L>(
+            ctx,
+            host,
+            public_key,
+            self.seen_sequence,
+            self.remote_whitelist.clone(),
+            self.fallback.clone(),
+        )
+    }
+
+    pub fn query(&self, host: impl Display, public_key: Option<A::PublicKey>) -> QueryStream<K> {
+        without_origin(self.query_with_context(self.context(), host.to_string(), public_key))
+    }
+
+    /// Like `query`, but pairs each ENR with the domain and root sequence of the tree that
+    /// listed it. ENRs of linked trees carry the sequence of their own root.
+    pub fn query_with_origin(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> OriginQueryStream<K> {
+        self.query_with_context(self.context(), host.to_string(), public_key)
+    }
+
+    /// Like `query`, but also returns a handle reporting how the query ended once its stream
+    /// is consumed.
+    pub fn query_with_outcome(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> (QueryStream<K>, QueryOutcome) {
+        let ctx = self.context();
+        let outcome = ctx.outcome.clone();
+
+        (
+            without_origin(self.query_with_context(ctx, host.to_string(), public_key)),
+            outcome,
+        )
+    }
+
+    /// Like `query`, but reports records that fail to resolve, parse or verify on a separate
+    /// stream and skips them instead of failing the query. The root of `host` is still required
+    /// to verify.
+    pub fn query_with_diagnostics(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> (QueryStream<K>, DiagnosticStream) {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let ctx = Arc::new(self.context_with_diagnostics(Some(tx)));
+        let diagnostics = Box::pin(stream! {
+            while let Some(diagnostic) = rx.recv().await {
+                yield diagnostic;
+            }
+        });
+
+        (
+            without_origin(self.query_with_context(ctx, host.to_string(), public_key)),
+            diagnostics,
+        )
+    }
+
+    fn query_with_context(
+        &self,
+        ctx: Arc<QueryContext<B, K>>,
+        host: String,
+        public_key: Option<A::PublicKey>,
+    ) -> OriginQueryStream<K> {
+        let id = match &self.query_ids {
+            Some(ids) => ids.fetch_add(1, Ordering::Relaxed),
+            None => rand::random(),
+        };
+        let span = debug_span!("query", id, host = %host);
+        span.in_scope(|| {
+            debug!(
+                "Starting DNS discovery query: seen_sequence={:?}, whitelist={:?}",
+                self.seen_sequence,
+                self.remote_whitelist
+                    .as_ref()
+                    .map(|whitelist| whitelist.keys().collect::<Vec<_>>())
+            )
+        });
+        let outcome = ctx.outcome.clone();
+        let mut s = self.query_sourced::<Enr<K>>(ctx, host, public_key);
+        let s: OriginQueryStream<K> = if self.sorted_output {
+            Box::pin(try_stream! {
+                let mut records = vec![];
+                while let Some(record) = s.try_next().await? {
+                    records.push(record);
+                }
+                records.sort_by_cached_key(|(origin, record)| {
+                    (origin.domain.clone(), record_hash(&record.to_base64()))
+                });
+                for (origin, record) in records {
+                    yield (record, origin);
+                }
+            })
+        } else {
+            Box::pin(s.map(|record| record.map(|(origin, record)| (record, origin))))
+        };
+        let s = match self.adaptive_stop {
+            Some(stop) => stop_adaptively(s, stop, outcome),
+            None => s,
+        };
+
+        let mut s = match self.emit_interval {
+            Some(interval) => Box::pin(s.throttle(interval)),
+            None => s,
+        };
+
+        // Lookups spawned while polling inherit the span.
+        Box::pin(stream! {
+            while let Some(record) = s.next().instrument(span.clone()).await {
+                yield record;
+            }
+        })
+    }
+
+    /// Like `query`, but yields the text of ENRs without decoding them, with the EIP-1459 prefix
+    /// and an unpadded base64url payload. Links are followed and records are checked the same
+    /// way, and every record is also checked against the label it is published under. ENR
+    /// filters, deduplication and `with_skip_unsupported_enrs` are not applied, as they need
+    /// decoded records, except in `with_verify_then_resolve` mode, which decodes ENRs while
+    /// verifying their subtree.
+    pub fn query_raw(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> RawQueryStream {
+        let mut ctx = self.context_with_diagnostics(None);
+        ctx.verify_hashes = true;
+        let s = self.query_sourced(Arc::new(ctx), host.to_string(), public_key);
+        Box::pin(s.map(|record| record.map(|(_, text)| text)))
+    }
+
+    /// Describes what a query for `host` would do with this configuration, without looking
+    /// anything up.
+    pub fn dry_run(&self, host: impl Display, public_key: Option<A::PublicKey>) -> String {
+        let mut plan = vec![match public_key {
+            Some(public_key) => format!(
+                "would query root at {} and verify it against {}",
+                host,
+                hex::encode(public_key.encode())
+            ),
+            None => format!("would query root at {} without verifying it", host),
+        }];
+        if let Some(seen_sequence) = self.seen_sequence {
+            plan.push(format!(
+                "would stop if its sequence is {} or lower",
+                seen_sequence
+            ));
+        }
+        plan.push(match &self.remote_whitelist {
+            Some(whitelist) if whitelist.is_empty() => "would not follow any links".to_string(),
+            Some(whitelist) => {
+                let mut domains = whitelist.keys().cloned().collect::<Vec<_>>();
+                domains.sort_unstable();
+                format!("would follow links to {} only", domains.join(", "))
+            }
+            None => "would follow links to any domain".to_string(),
+        });
+        if self.follow_inline_links {
+            plan.push("would also follow links in the ENR subtree".to_string());
+        }
+        plan.push(if self.verify_then_resolve {
+            "would verify the link and ENR subtrees in full before resolving them".to_string()
+        } else {
+            "would resolve the link and ENR subtrees".to_string()
+        });
+        if let Some(max_records) = self.max_records {
+            plan.push(format!("would stop after {} records", max_records));
+        }
+
+        plan.join(", ")
+    }
+
+    /// Fetches and parses a single record, without any tree traversal or verification.
+    pub async fn resolve_single(&self, fqdn: String) -> anyhow::Result<Option<DnsRecord<K, A>>> {
+        let record = self.backend.get_record(fqdn.clone()).await?;
+        check_txt_size(fqdn, record, self.max_txt_size)?
+            .map(|record| DnsRecord::parse(&record, self.strict_branches, &self.scheme))
+            .transpose()
+    }
+
+    /// Fetches the root of `host` and returns its exact text along with the parsed form, so
+    /// that the tree can be mirrored without re-signing it.
+    pub async fn fetch_root(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<(String, RootRecord)> {
+        let host = host.to_string();
+        let ctx = self.context();
+        let text = ctx
+            .get_root(&host)
+            .await?
+            .ok_or_else(|| anyhow!("No records found for tree {}", host))?;
+        let root = raw::check_root(ctx.parse::<A>(&text, false)?, public_key.as_ref())?;
+
+        Ok((text, root))
+    }
+
+    /// Like `query`, but pairs each ENR with the time it was received.
+    pub fn query_with_timestamp(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> TimestampedQueryStream<K> {
+        Box::pin(
+            self.query(host, public_key)
+                .map(|record| record.map(|record| (record, SystemTime::now()))),
+        )
+    }
+
+    /// Resolves the whole tree without returning the records, only their count. Lets a caching
+    /// backend be warmed up ahead of the queries it is meant to serve.
+    pub async fn prefetch(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<usize> {
+        self.count_enrs(host, public_key).await
+    }
+
+    /// Resolves the whole tree and counts the ENRs that would be yielded, dropping each one as
+    /// it arrives.
+    pub async fn count_enrs(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<usize> {
+        let mut s = self.query(host, public_key);
+        let mut count = 0;
+        while s.try_next().await?.is_some() {
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Walks the ENR subtree of the tree at `host` and reports its shape, without checking
+    /// the leaves or following links. The tree is walked one lookup at a time, within the
+    /// query budget and record limit of this resolver.
+    pub async fn tree_shape(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<TreeShape> {
+        let host = host.to_string();
+        let ctx = self.context();
+        let root = ctx
+            .get_root(&host)
+            .await?
+            .ok_or_else(|| anyhow!("No records found for tree {}", host))?;
+        let root = raw::check_root(ctx.parse::<A>(&root, false)?, public_key.as_ref())?;
+
+        let mut shape = TreeShape::default();
+        let mut pending = vec![(root.base.enr_root, 0)];
+        while let Some((label, depth)) = pending.pop() {
+            let text = match ctx.get_child(&host, format!("{}.{}", label, host)).await? {
+                (Some(text), _) => text,
+                (None, _) => {
+                    warn!("Child {} is empty", label);
+                    continue;
+                }
+            };
+
+            if let Some(children) =
+                raw::expand_branch(&ctx.parse::<A>(&text, self.strict_branches)?)
+            {
+                shape.branch_count += 1;
+                shape.max_branch_width = shape.max_branch_width.max(children.len());
+                pending.extend(children.into_iter().map(|child| (child, depth + 1)));
+            } else {
+                shape.leaf_count += 1;
+                shape.depth = shape.depth.max(depth);
+            }
+        }
+
+        Ok(shape)
+    }
+
+    /// Collects a query into a map keyed by node id, keeping the version of each node with the
+    /// highest sequence number. Records that fail to resolve or parse are skipped and the rest
+    /// of the tree is still walked, but failing to fetch or verify the root is an error. Stops
+    /// once `limit` nodes are found.
+    pub async fn collect_map(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<HashMap<NodeId, Enr<K>>> {
+        let mut nodes = HashMap::<NodeId, Enr<K>>::new();
+        let (mut s, _) = self.query_with_diagnostics(host, public_key);
+        while limit.map_or(true, |limit| nodes.len() < limit) {
+            match s.try_next().await? {
+                None => break,
+                Some(record) => {
+                    if nodes
+                        .get(&record.node_id())
+                        .map_or(true, |old| old.seq() < record.seq())
+                    {
+                        nodes.insert(record.node_id(), record);
+                    }
+                }
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    /// Resolves the tree and collects the distinct IPv4 and IPv6 addresses its ENRs advertise.
+    pub async fn resolve_addresses(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<HashSet<IpAddr>> {
+        let mut addresses = HashSet::new();
+        let mut s = self.query(host, public_key);
+        while let Some(record) = s.try_next().await? {
+            addresses.extend(record.ip().map(IpAddr::from));
+            addresses.extend(record.ip6().map(IpAddr::from));
+        }
+
+        Ok(addresses)
+    }
+
+    /// Resolves the tree and merges its ENRs into `nodes`, keeping the ENR with the highest
+    /// sequence number for each node.
+    pub async fn sync_into(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+        nodes: &Mutex<HashMap<NodeId, Enr<K>>>,
+    ) -> anyhow::Result<SyncCounts> {
+        let mut counts = SyncCounts::default();
+        let mut s = self.query(host, public_key);
+        while let Some(record) = s.try_next().await? {
+            let mut nodes = nodes.lock().unwrap();
+            match nodes.get(&record.node_id()) {
+                None => counts.added += 1,
+                Some(existing) if existing.seq() < record.seq() => counts.updated += 1,
+                Some(_) => continue,
+            }
+            nodes.insert(record.node_id(), record);
+        }
+
+        Ok(counts)
+    }
+
+    /// Resolves the tree into a JSON array with the text, node id, IP address and port of each
+    /// ENR. IPv4 endpoints are preferred over IPv6 ones, and TCP ports over UDP ones.
+    #[cfg(feature = "serde")]
+    pub async fn query_to_json(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut records = vec![];
+        let mut s = self.query(host, public_key);
+        while let Some(record) = s.try_next().await? {
+            let (ip, port) = match record.ip() {
+                Some(ip) => (
+                    Some(IpAddr::from(ip)),
+                    record.tcp().or_else(|| record.udp()),
+                ),
+                None => (
+                    record.ip6().map(IpAddr::from),
+                    record.tcp6().or_else(|| record.udp6()),
+                ),
+            };
+            records.push(serde_json::json!({
+                "enr": record.to_base64(),
+                "nodeId": hex::encode(record.node_id().raw()),
+                "ip": ip.map(|ip| ip.to_string()),
+                "port": port,
+            }));
+        }
+
+        Ok(serde_json::Value::Array(records))
+    }
+
+    /// Liveness check for a tree. Succeeds with the first ENR that passes verification and
+    /// cancels all remaining lookups.
+    pub async fn probe(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<Enr<K>> {
+        let host = host.to_string();
+        let ctx = self.context();
+        let mut s = self.query_sourced(ctx.clone(), host.clone(), public_key);
+        let record = s.try_next().await;
+        ctx.cancel();
+
+        record?
+            .map(|(_, record)| record)
+            .ok_or_else(|| anyhow!("No ENRs found in tree {}", host))
+    }
+
+    /// Resolves the tree and groups its ENRs by the domain of the tree that published them,
+    /// whether that is `host` itself or a tree linked from it.
+    pub async fn resolve_grouped(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+    ) -> anyhow::Result<HashMap<String, Vec<Enr<K>>>> {
+        let mut s = self.query_sourced(self.context(), host.to_string(), public_key);
+        let mut groups = HashMap::<_, Vec<_>>::new();
+        while let Some((origin, record)) = s.try_next().await? {
+            groups.entry(origin.domain).or_default().push(record);
+        }
+
+        Ok(groups)
+    }
+
+    /// Drains a query into `path`, one base64 ENR per line. Returns the number of records written.
+    pub async fn query_to_file(
+        &self,
+        host: impl Display,
+        public_key: Option<A::PublicKey>,
+        path: &Path,
+    ) -> anyhow::Result<usize> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        let mut s = self.query(host, public_key);
+        let mut written = 0;
+        while let Some(record) = s.try_next().await? {
+            file.write_all(record.to_base64().as_bytes()).await?;
+            file.write_all(b"\n").await?;
+            written += 1;
+        }
+        file.flush().await?;
+
+        Ok(written)
+    }
+
+    pub fn query_tree(&self, tree_link: impl AsRef<str>) -> QueryStream<K> {
+        match DnsRecord::<K, A>::parse_with_scheme(tree_link.as_ref(), &self.scheme).and_then(
+            |link| {
+                if let DnsRecord::Link { public_key, domain } = link {
+                    info!("{}/{}", domain, hex::encode(public_key.encode()));
+                    Ok((public_key, domain))
+                } else {
+                    bail!("Unexpected record type")
+                }
+            },
+        ) {
+            Ok((public_key, domain)) => self.query(domain, Some(public_key)),
+            Err(e) => Box::pin(tokio_stream::once(Err(e))),
+        }
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+impl<K: EnrKey, A: EnrKeyUnambiguous> Resolver<trust_dns_resolver::TokioAsyncResolver, K, A> {
+    /// Resolves trees through a trust-dns resolver the application already uses for other
+    /// lookups. The resolver is safe to use concurrently, so queries share its connections
+    /// and cache with the rest of the application.
+    pub fn from_shared_resolver(resolver: &Arc<trust_dns_resolver::TokioAsyncResolver>) -> Self {
+        Self::new(resolver.clone())
+    }
+}
+
+/// Reads ENRs written by `Resolver::query_to_file`, skipping blank lines.
+pub async fn load_enrs_from_file<K: EnrKey>(path: &Path) -> anyhow::Result<Vec<Enr<K>>> {
+    tokio::fs::read_to_string(path)
+        .await?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(parse_enr(line)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use enr::{ed25519_dalek, CombinedKey, EnrBuilder, EnrKey};
+    use k256::{
+        ecdsa::{SigningKey, VerifyingKey},
+        EncodedPoint,
+    };
+    use maplit::{hashmap, hashset};
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::atomic::AtomicUsize,
+    };
+    use tracing_subscriber::EnvFilter;
+
+    static_assertions::assert_impl_all!(DnsRecord<SigningKey>: Send, Sync);
+
+    const EIP_DOMAIN: &str = "mynodes.org";
+    const EIP_RECORDS: &[(Option<&str>, &str)] = &[
+        (
+            None,
+            "enrtree-root:v1 e=JWXYDBPXYWG6FX3GMDIBFA6CJ4 l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=1 sig=o908WmNp7LibOfPsr4btQwatZJ5URBr2ZAuxvK4UWHlsB9sUOTJQaGAlLPVAhM__XJesCHxLISo94z5Z2a463gA"
+        ), (
+            Some("C7HRFPF3BLGF3YR4DY5KX3SMBE"),
+            "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"
+        ), (
+            Some("JWXYDBPXYWG6FX3GMDIBFA6CJ4"),
+            "enrtree-branch:2XS2367YHAXJFGLZHVAWLQD4ZY,H4FHT4B454P6UXFD7JCYQ5PWDY,MHTDO6TMUBRIA2XWG5LUDACK24",
+        ), (
+            Some("2XS2367YHAXJFGLZHVAWLQD4ZY"),
+            "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA"
+        ), (
+            Some("H4FHT4B454P6UXFD7JCYQ5PWDY"),
+            "enr:-HW4QAggRauloj2SDLtIHN1XBkvhFZ1vtf1raYQp9TBW2RD5EEawDzbtSmlXUfnaHcvwOizhVYLtr7e6vw7NAf6mTuoCgmlkgnY0iXNlY3AyNTZrMaECjrXI8TLNXU0f8cthpAMxEshUyQlK-AM0PW2wfrnacNI"
+        ), (
+            Some("MHTDO6TMUBRIA2XWG5LUDACK24"),
+            "enr:-HW4QLAYqmrwllBEnzWWs7I5Ev2IAs7x_dZlbYdRdMUx5EyKHDXp7AV5CkuPGUPdvbv1_Ms1CPfhcGCvSElSosZmyoqAgmlkgnY0iXNlY3AyNTZrMaECriawHKWdDRk2xeZkrOXBQ0dfMFLHY4eENZwdufn1S1o"
+        )
+    ];
+
+    const EIP_ENR: &str = "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA";
+
+    /// In-memory backend that counts lookups and can simulate network latency.
+    #[derive(Default)]
+    struct MockBackend {
+        records: HashMap<String, String>,
+        delay: Duration,
+        delay_overrides: HashMap<String, Duration>,
+        lookups: AtomicUsize,
+        started: std::sync::Mutex<Vec<tokio::time::Instant>>,
+    }
+
+    impl MockBackend {
+        fn new(records: HashMap<String, String>) -> Self {
+            Self {
+                records,
+                ..Default::default()
+            }
+        }
+
+        fn with_delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        fn with_delay_for(mut self, fqdn: &str, delay: Duration) -> Self {
+            self.delay_overrides.insert(fqdn.to_string(), delay);
+            self
+        }
+
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+
+        fn lookup_starts(&self) -> Vec<tokio::time::Instant> {
+            self.started.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Backend for MockBackend {
+        async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.started
+                .lock()
+                .unwrap()
+                .push(tokio::time::Instant::now());
+            let delay = self
+                .delay_overrides
+                .get(&fqdn)
+                .copied()
+                .unwrap_or(self.delay);
+            if delay > Duration::from_secs(0) {
+                tokio::time::sleep(delay).await;
+            }
+            self.records.get_record(fqdn).await
+        }
+    }
+
+    fn test_records_to_hashmap(
+        domain: &str,
+        records: &[(Option<&str>, &str)],
+    ) -> HashMap<String, String> {
+        records
+            .iter()
+            .map(|(sub, entry)| {
+                (
+                    format!(
+                        "{}{}",
+                        sub.map(|s| format!("{}.", s)).unwrap_or_default(),
+                        domain
+                    ),
+                    entry.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn test_records_to_hashmap_geth(records: &[(&str, &str)]) -> HashMap<String, String> {
+        records
+            .iter()
+            .map(|(domain, entry)| (domain.to_string(), entry.to_string()))
+            .collect()
+    }
+
+    /// Pads a readable name out to a full-length hash label.
+    fn test_label(name: &str) -> String {
+        format!("{:A<width$}", name, width = BASE32_HASH_LEN)
+    }
+
+    /// Single-level tree with the given leaves and an empty link tree.
+    fn test_tree(domain: &str, leaves: &[String]) -> HashMap<String, String> {
+        let mut records = hashmap! {
+            domain.to_string() => "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig=".to_string(),
+            format!("LINKROOT.{}", domain) => "enrtree-branch:".to_string(),
+        };
+        let mut labels = vec![];
+        for (i, leaf) in leaves.iter().enumerate() {
+            let label = test_label(&format!("LEAF{}", i));
+            records.insert(format!("{}.{}", label, domain), leaf.clone());
+            labels.push(label);
+        }
+        records.insert(
+            format!("ENRROOT.{}", domain),
+            format!("enrtree-branch:{}", labels.join(",")),
+        );
+        records
+    }
+
+    fn test_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32]).unwrap()
+    }
+
+    fn test_enr(seed: u8, f: impl FnOnce(&mut EnrBuilder<SigningKey>)) -> Enr<SigningKey> {
+        let mut builder = EnrBuilder::new("v4");
+        f(&mut builder);
+        builder.build(&test_key(seed)).unwrap()
+    }
+
+    /// Replaces the unsigned root of a `test_tree` with one signed by the test key.
+    fn sign_test_tree(records: &mut HashMap<String, String>, domain: &str, seed: u8) {
+        let base = "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1";
+        let sig = test_key(seed).sign_v4(base.as_bytes()).unwrap();
+        records.insert(
+            domain.to_string(),
+            format!("{} sig={}", base, BASE64URL_NOPAD.encode(&sig)),
+        );
+    }
+
+    fn test_link(seed: u8, domain: &str) -> String {
+        format!(
+            "enrtree://{}@{}",
+            BASE32_NOPAD.encode(&test_key(seed).public().encode()),
+            domain
+        )
+    }
+
+    #[tokio::test]
+    async fn eip_example() {
+        let _ = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .try_init();
+
+        const DOMAIN: &str = "mynodes.org";
+        const TEST_RECORDS: &[(Option<&str>, &str)] = &[
+            (
+                None,
+                "enrtree-root:v1 e=JWXYDBPXYWG6FX3GMDIBFA6CJ4 l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=1 sig=o908WmNp7LibOfPsr4btQwatZJ5URBr2ZAuxvK4UWHlsB9sUOTJQaGAlLPVAhM__XJesCHxLISo94z5Z2a463gA"
+            ), (
+                Some("C7HRFPF3BLGF3YR4DY5KX3SMBE"),
+                "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"
+            ), (
+                Some("JWXYDBPXYWG6FX3GMDIBFA6CJ4"),
+                "enrtree-branch:2XS2367YHAXJFGLZHVAWLQD4ZY,H4FHT4B454P6UXFD7JCYQ5PWDY,MHTDO6TMUBRIA2XWG5LUDACK24",
+            ), (
+                Some("2XS2367YHAXJFGLZHVAWLQD4ZY"),
+                "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA"
+            ), (
+                Some("H4FHT4B454P6UXFD7JCYQ5PWDY"),
+                "enr:-HW4QAggRauloj2SDLtIHN1XBkvhFZ1vtf1raYQp9TBW2RD5EEawDzbtSmlXUfnaHcvwOizhVYLtr7e6vw7NAf6mTuoCgmlkgnY0iXNlY3AyNTZrMaECjrXI8TLNXU0f8cthpAMxEshUyQlK-AM0PW2wfrnacNI"
+            ), (
+                Some("MHTDO6TMUBRIA2XWG5LUDACK24"),
+                "enr:-HW4QLAYqmrwllBEnzWWs7I5Ev2IAs7x_dZlbYdRdMUx5EyKHDXp7AV5CkuPGUPdvbv1_Ms1CPfhcGCvSElSosZmyoqAgmlkgnY0iXNlY3AyNTZrMaECriawHKWdDRk2xeZkrOXBQ0dfMFLHY4eENZwdufn1S1o"
+            )
+        ];
+
+        let data = test_records_to_hashmap(DOMAIN, TEST_RECORDS);
+
+        let mut s = Resolver::<_, SigningKey>::new(Arc::new(data))
+            .with_remote_whitelist(Arc::new(hashmap!{
+                "morenodes.example.org".to_string() => VerifyingKey::from_encoded_point(&EncodedPoint::from_bytes(&hex::decode("049f88229042fef9200246f49f94d9b77c4e954721442714e85850cb6d9e5daf2d880ea0e53cb3ac1a75f9923c2726a4f941f7d326781baa6380754a360de5c2b6").unwrap()).unwrap()).unwrap()
+            }))
+            .query(DOMAIN.to_string(), None);
+        let mut out = HashSet::new();
+        while let Some(record) = s.try_next().await.unwrap() {
+            assert!(out.insert(record.to_base64()));
+        }
+        assert_eq!(
+            out,
+            hashset![
+                "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA",
+                "enr:-HW4QAggRauloj2SDLtIHN1XBkvhFZ1vtf1raYQp9TBW2RD5EEawDzbtSmlXUfnaHcvwOizhVYLtr7e6vw7NAf6mTuoCgmlkgnY0iXNlY3AyNTZrMaECjrXI8TLNXU0f8cthpAMxEshUyQlK-AM0PW2wfrnacNI",
+                "enr:-HW4QLAYqmrwllBEnzWWs7I5Ev2IAs7x_dZlbYdRdMUx5EyKHDXp7AV5CkuPGUPdvbv1_Ms1CPfhcGCvSElSosZmyoqAgmlkgnY0iXNlY3AyNTZrMaECriawHKWdDRk2xeZkrOXBQ0dfMFLHY4eENZwdufn1S1o",
+            ].into_iter().map(ToString::to_string).collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn tree_shape() {
+        let shape = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )))
+        .tree_shape(EIP_DOMAIN, None)
+        .await
+        .unwrap();
+        assert_eq!(
+            shape,
+            TreeShape {
+                depth: 1,
+                max_branch_width: 3,
+                branch_count: 1,
+                leaf_count: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn renamed_scheme() {
+        let scheme = RecordScheme {
+            root: "acmetree-root:v1".to_string(),
+            link: "acmetree://".to_string(),
+            branch: "acmetree-branch:".to_string(),
+            enr: "acme:".to_string(),
+        };
+        let rename = |text: &str| {
+            text.replacen("enrtree", "acmetree", 1)
+                .replacen("enr:", "acme:", 1)
+        };
+        let mut data = test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS)
+            .into_iter()
+            .map(|(fqdn, text)| (fqdn, rename(&text)))
+            .collect::<HashMap<_, _>>();
+
+        // The root prefix is part of the signed text.
+        let base = rename(
+            "enrtree-root:v1 e=JWXYDBPXYWG6FX3GMDIBFA6CJ4 l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=1",
+        );
+        let sig = test_key(1).sign_v4(base.as_bytes()).unwrap();
+        data.insert(
+            EIP_DOMAIN.to_string(),
+            format!("{} sig={}", base, BASE64URL_NOPAD.encode(&sig)),
+        );
+        let data = Arc::new(data);
+
+        let out = Resolver::<_, SigningKey>::new(data.clone())
+            .with_scheme(scheme.clone())
+            .with_remote_whitelist(Arc::new(HashMap::new()))
+            .query(EIP_DOMAIN, Some(test_key(1).public()))
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            out,
+            EIP_RECORDS[3..]
+                .iter()
+                .map(|(_, text)| text.to_string())
+                .collect()
+        );
+
+        for text in data.values() {
+            let record = DnsRecord::<SigningKey>::parse_with_scheme(text, &scheme).unwrap();
+            match record {
+                DnsRecord::Root(root) => assert_eq!(root.base.to_string(), base),
+                DnsRecord::Enr { .. } => assert_eq!(&record.to_string_with_scheme(&scheme), text),
+                _ => {}
+            }
+        }
+
+        // Default resolvers do not recognize the renamed tree.
+        assert!(Resolver::<_, SigningKey>::new(data)
+            .query(EIP_DOMAIN, None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn sloppy_base64_enrs() {
+        let leaves = EIP_RECORDS[3..]
+            .iter()
+            .map(|(_, text)| text.to_string())
+            .collect::<Vec<_>>();
+        let padded = leaves
+            .iter()
+            .map(|leaf| {
+                let padding = (4 - (leaf.len() - ENR_PREFIX.len()) % 4) % 4;
+                format!("{}{}", leaf, "=".repeat(padding))
+            })
+            .collect::<Vec<_>>();
+        let standard = leaves
+            .iter()
+            .map(|leaf| leaf.replace('-', "+").replace('_', "/"))
+            .collect::<Vec<_>>();
+        assert_ne!(padded, leaves);
+        assert_ne!(standard, leaves);
+
+        for variants in &[&padded, &standard] {
+            for (variant, leaf) in variants.iter().zip(&leaves) {
+                let record = variant.parse::<DnsRecord<SigningKey>>().unwrap();
+                assert_eq!(&record.to_string(), leaf);
+                let err = DnsRecord::<SigningKey>::parse_strict(variant).unwrap_err();
+                assert!(err.is::<InvalidEnrEncoding>());
+            }
+        }
+        let err = format!("{}!", leaves[0])
+            .parse::<DnsRecord<SigningKey>>()
+            .unwrap_err();
+        assert!(err.is::<InvalidEnrEncoding>());
+
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &[padded[0].clone(), standard[1].clone(), leaves[2].clone()],
+        )));
+        let res = resolver
+            .query("n", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(resolver.stats().normalized_enrs(), 2);
+
+        let err = resolver
+            .with_strict_branches(true)
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        assert!(err.is::<InvalidEnrEncoding>());
+    }
+
+    #[tokio::test]
+    async fn bad_node() {
+        const TEST_RECORDS: &[(&str, &str)] = &[
+            ("n",                            "enrtree-root:v1 e=INDMVBZEEQ4ESVYAKGIYU74EAA l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=3 sig=Vl3AmunLur0JZ3sIyJPSH6A3Vvdp4F40jWQeCmkIhmcgwE4VC5U9wpK8C_uL_CMY29fd6FAhspRvq2z_VysTLAA"),
+		    ("C7HRFPF3BLGF3YR4DY5KX3SMBE.n", "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"),
+		    ("INDMVBZEEQ4ESVYAKGIYU74EAA.n", "enr:-----"),
+        ];
+
+        let data = test_records_to_hashmap_geth(TEST_RECORDS);
 
         let err = Resolver::<_, SigningKey>::new(Arc::new(data))
             .query_tree("enrtree://AKPYQIUQIL7PSIACI32J7FGZW56E5FKHEFCCOFHILBIMW3M6LWXS2@n")
             .collect::<Result<Vec<_>, _>>()
             .await
             .unwrap_err();
-        if !err.chain().any(std::error::Error::is::<InvalidEnr>) {
-            unreachable!("should have seen the correct error")
+        if !err.chain().any(std::error::Error::is::<InvalidEnr>) {
+            unreachable!("should have seen the correct error")
+        }
+    }
+
+    #[test]
+    fn try_into_variants() {
+        let record = DnsRecord::<SigningKey>::from_str(
+            "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org",
+        )
+        .unwrap();
+
+        let record = record.try_into_root().unwrap_err();
+        let record = record.try_into_branch().unwrap_err();
+        let record = record.try_into_enr().unwrap_err();
+        let (_, domain) = record.try_into_link().unwrap();
+        assert_eq!(domain, "morenodes.example.org");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fail_fast_cancels_siblings() {
+        const DOMAIN: &str = "n";
+
+        let mut records = hashmap! {
+            DOMAIN.to_string() => "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig=".to_string(),
+            format!("LINKROOT.{}", DOMAIN) => "enrtree-branch:".to_string(),
+            format!("{}.{}", test_label("BAD"), DOMAIN) => "enr:-----".to_string(),
+        };
+        let mut top = vec![test_label("BAD")];
+        for i in 0..10 {
+            let branch = test_label(&format!("C{}", i));
+            let mut leaves = vec![];
+            for j in 0..20 {
+                let leaf = test_label(&format!("C{}L{}", i, j));
+                records.insert(format!("{}.{}", leaf, DOMAIN), EIP_ENR.to_string());
+                leaves.push(leaf);
+            }
+            records.insert(
+                format!("{}.{}", branch, DOMAIN),
+                format!("enrtree-branch:{}", leaves.join(",")),
+            );
+            top.push(branch);
+        }
+        // Children are resolved in sorted order, so the bad one goes first.
+        top.sort();
+        assert_eq!(top[0], test_label("BAD"));
+        records.insert(
+            format!("ENRROOT.{}", DOMAIN),
+            format!("enrtree-branch:{}", top.join(",")),
+        );
+
+        // The bad leaf fails at once, while its siblings are still being looked up.
+        let backend = Arc::new(
+            MockBackend::new(records)
+                .with_delay(Duration::from_secs(1))
+                .with_delay_for(&format!("{}.{}", test_label("BAD"), DOMAIN), Duration::ZERO),
+        );
+        Resolver::<_, SigningKey>::new(backend.clone())
+            .query(DOMAIN, None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+
+        // Give any surviving tasks a chance to run to completion.
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        // Root, link root, ENR root and its direct children at most.
+        assert!(backend.lookups() <= 14, "{} lookups", backend.lookups());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn per_domain_timeouts() {
+        let backend = Arc::new(
+            MockBackend::new(test_records_to_hashmap_geth(&[
+                ("n", "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig="),
+                ("LINKROOT.n", "enrtree-branch:"),
+                ("ENRROOT.n", EIP_ENR),
+            ]))
+            .with_delay(Duration::from_secs(5)),
+        );
+
+        let res = Resolver::<_, SigningKey>::new(backend.clone())
+            .with_per_domain_timeouts(hashmap! { "n".to_string() => Duration::from_secs(10) })
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+
+        let err = Resolver::<_, SigningKey>::new(backend)
+            .with_per_domain_timeouts(hashmap! { "n".to_string() => Duration::from_secs(1) })
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Timed out"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn emit_rate() {
+        let mut s = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )))
+        .with_emit_rate(2)
+        .query(EIP_DOMAIN, None);
+
+        let mut last = None;
+        let mut total = 0;
+        while s.try_next().await.unwrap().is_some() {
+            let now = tokio::time::Instant::now();
+            if let Some(last) = last {
+                assert!(now - last >= Duration::from_millis(500));
+            }
+            last = Some(now);
+            total += 1;
+        }
+        assert_eq!(total, 3);
+    }
+
+    /// One instantly resolvable ENR next to ten slow branches of twenty leaves each.
+    fn wide_test_backend() -> Arc<MockBackend> {
+        const DOMAIN: &str = "n";
+
+        let mut records = hashmap! {
+            DOMAIN.to_string() => "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig=".to_string(),
+            format!("LINKROOT.{}", DOMAIN) => "enrtree-branch:".to_string(),
+            format!("{}.{}", test_label("FAST"), DOMAIN) => EIP_ENR.to_string(),
+        };
+        let mut top = vec![test_label("FAST")];
+        for i in 0..10 {
+            let branch = test_label(&format!("B{}", i));
+            let mut leaves = vec![];
+            for j in 0..20 {
+                let leaf = test_label(&format!("B{}L{}", i, j));
+                records.insert(format!("{}.{}", leaf, DOMAIN), EIP_ENR.to_string());
+                leaves.push(leaf);
+            }
+            records.insert(
+                format!("{}.{}", branch, DOMAIN),
+                format!("enrtree-branch:{}", leaves.join(",")),
+            );
+            top.push(branch);
+        }
+        records.insert(
+            format!("ENRROOT.{}", DOMAIN),
+            format!("enrtree-branch:{}", top.join(",")),
+        );
+
+        Arc::new(
+            MockBackend::new(records)
+                .with_delay(Duration::from_secs(1))
+                .with_delay_for(&format!("{}.n", test_label("FAST")), Duration::from_secs(0)),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_when_consumer_gone() {
+        let backend = wide_test_backend();
+        let mut s = Resolver::<_, SigningKey>::new(backend.clone()).query("n", None);
+        s.try_next().await.unwrap().unwrap();
+        drop(s);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        // Nothing below the first level of branches is ever looked up.
+        assert_eq!(backend.lookups(), 14);
+    }
+
+    #[tokio::test]
+    async fn require_public_ip() {
+        let public = test_enr(1, |b| {
+            b.ip("1.2.3.4".parse().unwrap()).tcp(30303);
+        });
+        let no_ip = test_enr(2, |_| {});
+        let private = test_enr(3, |b| {
+            b.ip("192.168.1.1".parse().unwrap()).tcp(30303);
+        });
+        let link_local = test_enr(4, |b| {
+            b.ip("fe80::1".parse().unwrap()).tcp6(30303);
+        });
+
+        let data = Arc::new(test_tree(
+            "n",
+            &[&public, &no_ip, &private, &link_local]
+                .iter()
+                .map(|enr| enr.to_base64())
+                .collect::<Vec<_>>(),
+        ));
+
+        let all = Resolver::<_, SigningKey>::new(data.clone())
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 4);
+
+        let filtered = Resolver::<_, SigningKey>::new(data)
+            .with_require_public_ip(true)
+            .query("n", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(filtered, hashset![public.to_base64(), no_ip.to_base64()]);
+    }
+
+    #[test]
+    fn special_purpose_ips() {
+        for ip in &[
+            "0.1.2.3",
+            "10.0.0.1",
+            "100.64.0.1",
+            "100.127.255.254",
+            "127.0.0.1",
+            "127.255.255.254",
+            "169.254.1.1",
+            "172.16.0.1",
+            "172.31.255.254",
+            "192.0.0.8",
+            "192.0.2.1",
+            "192.88.99.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "198.19.255.254",
+            "198.51.100.1",
+            "203.0.113.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:1.2.3.4",
+            "64:ff9b:1::1",
+            "100::1",
+            "2001::1",
+            "2001:1ff::1",
+            "2001:db8::1",
+            "2002::1",
+            "3fff::1",
+            "5f00::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "febf::1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} is public", ip);
+        }
+
+        for ip in &[
+            "1.2.3.4",
+            "9.255.255.255",
+            "11.0.0.1",
+            "100.128.0.1",
+            "172.32.0.1",
+            "192.0.3.1",
+            "198.20.0.1",
+            "223.255.255.254",
+            "2001:200::1",
+            "2001:4860:4860::8888",
+            "2003::1",
+            "2a00:1450::1",
+            "fec0::1",
+        ] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} is not public", ip);
+        }
+    }
+
+    #[test]
+    fn branch_with_limit() {
+        let labels = ["A", "B", "A", "C", "D"]
+            .iter()
+            .map(|label| test_label(label).parse().unwrap())
+            .collect::<Vec<Base32Hash>>();
+
+        let branch = DnsRecord::<SigningKey>::branch_with_limit(labels.clone(), 3)
+            .try_into_branch()
+            .unwrap();
+        assert_eq!(branch, hashset![labels[0], labels[1], labels[3]]);
+
+        assert_eq!(
+            DnsRecord::<SigningKey>::branch_with_limit(labels, 10)
+                .try_into_branch()
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn socket_addrs() {
+        let record = DnsRecord::<SigningKey>::Enr {
+            record: test_enr(1, |b| {
+                b.ip("203.0.113.1".parse().unwrap())
+                    .tcp(30303)
+                    .udp(30301)
+                    .ip("2001:db8::1".parse().unwrap())
+                    .udp6(30303);
+            }),
+        };
+        assert_eq!(
+            record.socket_addrs(),
+            vec![
+                "203.0.113.1:30303".parse().unwrap(),
+                "203.0.113.1:30301".parse().unwrap(),
+                "[2001:db8::1]:30303".parse().unwrap(),
+            ]
+        );
+
+        let same_port = DnsRecord::<SigningKey>::Enr {
+            record: test_enr(1, |b| {
+                b.ip("203.0.113.1".parse().unwrap()).tcp(30303).udp(30303);
+            }),
+        };
+        assert_eq!(
+            same_port.socket_addrs(),
+            vec!["203.0.113.1:30303".parse::<SocketAddr>().unwrap()]
+        );
+
+        assert!(DnsRecord::<SigningKey>::Branch {
+            children: HashSet::new()
+        }
+        .socket_addrs()
+        .is_empty());
+    }
+
+    #[tokio::test]
+    async fn require_endpoint() {
+        let v4 = test_enr(1, |b| {
+            b.ip("1.2.3.4".parse().unwrap()).udp(30303);
+        });
+        let v6 = test_enr(2, |b| {
+            b.ip("2001:db8::1".parse().unwrap()).tcp6(30303);
+        });
+        let no_endpoint = test_enr(3, |_| {});
+        let no_port = test_enr(4, |b| {
+            b.ip("1.2.3.4".parse().unwrap());
+        });
+
+        let data = Arc::new(test_tree(
+            "n",
+            &[&v4, &v6, &no_endpoint, &no_port]
+                .iter()
+                .map(|enr| enr.to_base64())
+                .collect::<Vec<_>>(),
+        ));
+
+        let filtered = Resolver::<_, SigningKey>::new(data)
+            .with_require_endpoint(true)
+            .query("n", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(filtered, hashset![v4.to_base64(), v6.to_base64()]);
+    }
+
+    async fn assert_wrong_record_kind(
+        records: &[(&str, &str)],
+        label: &str,
+        expected: RecordKind,
+        found: RecordKind,
+        parent: Option<&str>,
+    ) {
+        let err = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap_geth(records)))
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        let err = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<WrongRecordKind>())
+            .unwrap();
+        assert_eq!(err.label.as_str(), label);
+        assert_eq!(err.expected, expected);
+        assert_eq!(err.found, found);
+        assert_eq!(err.parent.as_ref().map(|p| p.as_str()), parent);
+    }
+
+    #[tokio::test]
+    async fn wrong_record_kind() {
+        assert_wrong_record_kind(
+            &[
+                ("n", "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig="),
+                ("LINKROOT.n", EIP_ENR),
+            ],
+            "LINKROOT",
+            RecordKind::Link,
+            RecordKind::Enr,
+            None,
+        )
+        .await;
+
+        let link = test_label("LINK");
+        assert_wrong_record_kind(
+            &[
+                ("n", "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig="),
+                ("LINKROOT.n", "enrtree-branch:"),
+                ("ENRROOT.n", &format!("enrtree-branch:{}", link)),
+                (&format!("{}.n", link), "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"),
+            ],
+            &link,
+            RecordKind::Enr,
+            RecordKind::Link,
+            Some("ENRROOT"),
+        )
+        .await;
+    }
+
+    /// Tree of six ENRs, the last three of which arrive 4, 8 and 12 seconds in.
+    fn front_loaded_backend() -> MockBackend {
+        let leaves = (1..=6)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let mut backend = MockBackend::new(test_tree("n", &leaves));
+        for (i, secs) in [(3, 4), (4, 8), (5, 12)] {
+            backend = backend.with_delay_for(
+                &format!("{}.n", test_label(&format!("LEAF{}", i))),
+                Duration::from_secs(secs),
+            );
+        }
+        backend
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adaptive_stop() {
+        let query = |stop| async move {
+            let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(front_loaded_backend()));
+            resolver.with_adaptive_stop(stop);
+            let (s, outcome) = resolver.query_with_outcome("n", None);
+            let found = s.collect::<Result<Vec<_>, _>>().await.unwrap().len();
+            (found, outcome.adaptive_stop())
+        };
+
+        let stop = AdaptiveStop {
+            min_nodes: 3,
+            idle: Duration::from_secs(5),
+            rate_floor: 0.0,
+        };
+        assert_eq!(query(stop).await, (6, None));
+        assert_eq!(
+            query(AdaptiveStop {
+                idle: Duration::from_secs(3),
+                ..stop
+            })
+            .await,
+            (3, Some(StopReason::Idle))
+        );
+        assert_eq!(
+            query(AdaptiveStop {
+                rate_floor: 0.5,
+                ..stop
+            })
+            .await,
+            (5, Some(StopReason::RateFloor))
+        );
+
+        // Each query reports its own outcome.
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(front_loaded_backend()));
+        resolver.with_adaptive_stop(AdaptiveStop {
+            idle: Duration::from_secs(3),
+            ..stop
+        });
+        let (s, stopped) = resolver.query_with_outcome("n", None);
+        let (mut partial, unfinished) = resolver.query_with_outcome("n", None);
+        partial.try_next().await.unwrap().unwrap();
+        assert_eq!(s.collect::<Vec<_>>().await.len(), 3);
+        assert_eq!(stopped.adaptive_stop(), Some(StopReason::Idle));
+        assert_eq!(unfinished.adaptive_stop(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter() {
+        let leaves = (1..=8)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let query = |seed| {
+            let leaves = leaves.clone();
+            async move {
+                let backend = Arc::new(MockBackend::new(test_tree("n", &leaves)));
+                let started = tokio::time::Instant::now();
+                let mut resolver = Resolver::<_, SigningKey>::new(backend.clone());
+                if let Some(seed) = seed {
+                    resolver
+                        .with_jitter(Duration::from_secs(1))
+                        .with_jitter_seed(seed);
+                }
+                let found = resolver
+                    .query("n", None)
+                    .collect::<Result<Vec<_>, _>>()
+                    .await
+                    .unwrap();
+                assert_eq!(found.len(), 8);
+
+                // Offsets of the lookups of the eight leaves, which start together.
+                let mut offsets = backend.lookup_starts()[3..]
+                    .iter()
+                    .map(|start| *start - started)
+                    .collect::<Vec<_>>();
+                offsets.sort();
+                offsets
+            }
+        };
+
+        let unjittered = query(None).await;
+        assert!(unjittered.iter().all(|offset| *offset == unjittered[0]));
+
+        let jittered = query(Some(1)).await;
+        assert!(jittered.windows(2).all(|w| w[0] < w[1]));
+        // The leaves wait for up to a second after three lookups of up to a second each.
+        assert!(jittered[7] - jittered[0] < Duration::from_secs(1));
+        assert!(*jittered.last().unwrap() < Duration::from_secs(4));
+        assert_eq!(query(Some(1)).await, jittered);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn record_timeouts() {
+        let fast = test_enr(1, |_| {}).to_base64();
+        let slow = test_enr(2, |_| {}).to_base64();
+        let [branch, fast_leaf, slow_leaf] =
+            [test_label("BRANCH"), test_label("FAST"), test_label("SLOW")];
+        let records = test_records_to_hashmap_geth(&[
+            ("n", "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig="),
+            ("LINKROOT.n", "enrtree-branch:"),
+            (
+                "ENRROOT.n",
+                &format!("enrtree-branch:{},{}", branch, slow_leaf),
+            ),
+            (
+                &format!("{}.n", branch),
+                &format!("enrtree-branch:{}", fast_leaf),
+            ),
+            (&format!("{}.n", fast_leaf), &fast),
+            (&format!("{}.n", slow_leaf), &slow),
+        ]);
+        let backend = MockBackend::new(records)
+            .with_delay_for(&format!("{}.n", branch), Duration::from_secs(3))
+            .with_delay_for(&format!("{}.n", slow_leaf), Duration::from_secs(3));
+
+        let res = Resolver::<_, SigningKey>::new(Arc::new(backend))
+            .with_record_timeouts(RecordTimeouts {
+                root: Duration::from_secs(1),
+                branch: Duration::from_secs(5),
+                leaf: Duration::from_secs(2),
+            })
+            .query("n", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res, vec![fast]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn query_budget() {
+        let backend = Arc::new(MockBackend::new(test_tree("n", &[EIP_ENR.to_string()])));
+        let mut resolver = Resolver::<_, SigningKey>::new(backend.clone());
+        resolver.with_query_budget(Arc::new(QueryBudget::per_hour(60)));
+
+        let started = tokio::time::Instant::now();
+        for _ in 0..10 {
+            let res = resolver
+                .query("n", None)
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .unwrap();
+            assert_eq!(res.len(), 1);
+        }
+
+        // One lookup up front, then one per minute.
+        assert_eq!(backend.lookups(), 40);
+        assert!(started.elapsed() >= Duration::from_secs(39 * 60));
+    }
+
+    #[tokio::test]
+    async fn txt_too_large() {
+        let mut records = test_tree("n", &[EIP_ENR.to_string()]);
+        let leaf = format!("{}.n", test_label("LEAF0"));
+        records.insert(
+            leaf.clone(),
+            format!("enrtree-branch:{}", "A".repeat(64 * 1024)),
+        );
+        let records = Arc::new(records);
+
+        let err = Resolver::<_, SigningKey>::new(records.clone())
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<TxtTooLarge>().unwrap();
+        assert_eq!(err.fqdn, leaf);
+        assert_eq!(err.size, 64 * 1024 + BRANCH_PREFIX.len());
+
+        assert!(Resolver::<_, SigningKey>::new(records)
+            .resolve_single(leaf)
+            .await
+            .unwrap_err()
+            .is::<TxtTooLarge>());
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn deterministic_ids() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(Level::DEBUG)
+                .with_ansi(false)
+                .with_writer({
+                    let logs = logs.clone();
+                    move || logs.clone()
+                })
+                .finish(),
+        );
+
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &[test_enr(1, |_| {}).to_base64()],
+        )));
+        resolver.with_deterministic_ids(7);
+        for _ in 0..2 {
+            resolver
+                .query("n", None)
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .unwrap();
+        }
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        for id in &[7, 8] {
+            let span = format!("query{{id={} host=n}}", id);
+            assert!(
+                logs.lines()
+                    .any(|line| line.contains(&span)
+                        && line.contains("Starting DNS discovery query")),
+                "{}",
+                logs
+            );
+            // Lookups spawned by the query log within its span too.
+            assert!(
+                logs.lines()
+                    .any(|line| line.contains(&span) && line.contains("resolving LEAF0")),
+                "{}",
+                logs
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn verbose_enrs() {
+        let record = test_enr(1, |b| {
+            b.ip("203.0.113.1".parse().unwrap()).tcp(30303).udp(30304);
+        });
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(Level::DEBUG)
+                .with_ansi(false)
+                .with_writer({
+                    let logs = logs.clone();
+                    move || logs.clone()
+                })
+                .finish(),
+        );
+
+        Resolver::<_, SigningKey>::new(Arc::new(test_tree("n", &[record.to_base64()])))
+            .with_verbose_enrs(true)
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Resolved ENR"))
+            .unwrap();
+        for field in &[
+            format!("node_id={}", hex::encode(record.node_id().raw())),
+            "ip=Some(203.0.113.1)".to_string(),
+            "tcp=Some(30303)".to_string(),
+            "udp=Some(30304)".to_string(),
+        ] {
+            assert!(line.contains(field.as_str()), "{} not in {}", field, line);
+        }
+    }
+
+    /// Answers every lookup below the root with a branch of ten new children.
+    #[derive(Default)]
+    struct ExplodingBackend {
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Backend for ExplodingBackend {
+        async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            if fqdn == "n" {
+                return Ok(Some(
+                    "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig=".to_string(),
+                ));
+            }
+
+            let children = (0..10)
+                .map(|i| record_hash(&format!("{}{}", fqdn, i)).to_string())
+                .collect::<Vec<_>>();
+            Ok(Some(format!("enrtree-branch:{}", children.join(","))))
+        }
+    }
+
+    #[tokio::test]
+    async fn max_records() {
+        let backend = Arc::new(ExplodingBackend::default());
+        let err = Resolver::<_, SigningKey>::new(backend.clone())
+            .with_max_records(1000)
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<TreeTooLarge>().unwrap().limit, 1000);
+
+        tokio::task::yield_now().await;
+        let lookups = backend.lookups.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(backend.lookups.load(Ordering::SeqCst), lookups);
+    }
+
+    #[test]
+    fn dry_run() {
+        let backend = Arc::new(MockBackend::new(test_tree("n", &[EIP_ENR.to_string()])));
+        let mut resolver = Resolver::<_, SigningKey>::new(backend.clone());
+        assert_eq!(
+            resolver.dry_run("n", None),
+            "would query root at n without verifying it, would follow links to any domain, \
+             would resolve the link and ENR subtrees"
+        );
+
+        resolver
+            .with_seen_sequence(3)
+            .with_remote_whitelist(Arc::new(hashmap! {
+                "b".to_string() => test_key(2).public(),
+                "a".to_string() => test_key(1).public(),
+            }))
+            .with_verify_then_resolve(true);
+        assert_eq!(
+            resolver.dry_run("n", Some(test_key(1).public())),
+            format!(
+                "would query root at n and verify it against {}, \
+                 would stop if its sequence is 3 or lower, would follow links to a, b only, \
+                 would verify the link and ENR subtrees in full before resolving them",
+                hex::encode(test_key(1).public().encode())
+            )
+        );
+        assert_eq!(backend.lookups(), 0);
+    }
+
+    #[tokio::test]
+    async fn combined_key_leaves() {
+        let secp256k1 = EnrBuilder::new("v4")
+            .build(&CombinedKey::from(test_key(1)))
+            .unwrap();
+        let ed25519 = EnrBuilder::new("v4")
+            .build(&CombinedKey::from(
+                ed25519_dalek::SecretKey::from_bytes(&[2; 32]).unwrap(),
+            ))
+            .unwrap();
+        let mut records = test_tree("n", &[secp256k1.to_base64(), ed25519.to_base64()]);
+        sign_test_tree(&mut records, "n", 9);
+
+        let found =
+            Resolver::<_, CombinedKey, SigningKey>::new(Arc::new(MockBackend::new(records)))
+                .query_tree(test_link(9, "n"))
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .unwrap()
+                .iter()
+                .map(Enr::node_id)
+                .collect::<HashSet<_>>();
+        assert_eq!(found, hashset![secp256k1.node_id(), ed25519.node_id()]);
+    }
+
+    struct UnreachableBackend;
+
+    #[async_trait]
+    impl Backend for UnreachableBackend {
+        async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+            bail!("Failed to reach name server for {}", fqdn)
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let enrs = vec![test_enr(1, |_| {}), test_enr(2, |_| {})];
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(UnreachableBackend));
+        assert!(resolver
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .is_err());
+
+        let found = resolver
+            .with_fallback(enrs.clone())
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(found, enrs);
+    }
+
+    #[cfg(all(feature = "ed25519", feature = "k256"))]
+    #[tokio::test]
+    async fn ed25519_tree() {
+        fn keypair(seed: u8) -> ed25519_dalek::Keypair {
+            let secret = ed25519_dalek::SecretKey::from_bytes(&[seed; 32]).unwrap();
+            ed25519_dalek::Keypair {
+                public: (&secret).into(),
+                secret,
+            }
+        }
+
+        let leaf = EnrBuilder::new("v4").build(&keypair(1)).unwrap();
+        let mut records = test_tree("n", &[leaf.to_base64()]);
+        let root = records["n"]
+            .parse::<DnsRecord<ed25519_dalek::Keypair>>()
+            .unwrap()
+            .try_into_root()
+            .unwrap();
+        let (enr_root, link_root) = (root.base.enr_root, root.base.link_root);
+        let root = root
+            .increment_sequence(enr_root, link_root, &keypair(9))
+            .unwrap();
+        records.insert("n".to_string(), root.to_string());
+
+        let link = DnsRecord::<ed25519_dalek::Keypair>::Link {
+            public_key: keypair(9).public,
+            domain: "n".to_string(),
+        }
+        .to_string();
+        let resolver = Resolver::<_, ed25519_dalek::Keypair>::new(Arc::new(records));
+        let res = resolver
+            .query_tree(&link)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res, vec![leaf]);
+
+        assert!(resolver
+            .query("n", Some(keypair(8).public))
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn split_horizon() {
+        let mut records = test_tree("n", &[EIP_ENR.to_string()]);
+        let apex = Arc::new(MockBackend::new(hashmap! {
+            "n".to_string() => records.remove("n").unwrap(),
+        }));
+        let leaves = Arc::new(MockBackend::new(records));
+
+        let res = Resolver::<_, SigningKey>::new(leaves.clone())
+            .with_apex_backend(apex.clone())
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(apex.lookups(), 1);
+        assert_eq!(leaves.lookups(), 3);
+    }
+
+    #[tokio::test]
+    async fn resolve_single() {
+        let resolver = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )));
+
+        let record = resolver
+            .resolve_single(format!("C7HRFPF3BLGF3YR4DY5KX3SMBE.{}", EIP_DOMAIN))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.kind(), RecordKind::Link);
+
+        assert!(resolver
+            .resolve_single(format!("AAAA.{}", EIP_DOMAIN))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn skip_duplicate_enrs() {
+        let [a, b, a1, b1] = [
+            test_label("A"),
+            test_label("B"),
+            test_label("A1"),
+            test_label("B1"),
+        ];
+        let records = test_records_to_hashmap_geth(&[
+            ("n", "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig="),
+            ("LINKROOT.n", "enrtree-branch:"),
+            ("ENRROOT.n", &format!("enrtree-branch:{},{}", a, b)),
+            (&format!("{}.n", a), &format!("enrtree-branch:{}", a1)),
+            (&format!("{}.n", b), &format!("enrtree-branch:{}", b1)),
+            (&format!("{}.n", a1), EIP_ENR),
+            (&format!("{}.n", b1), EIP_ENR),
+        ]);
+
+        let resolver = Resolver::<_, SigningKey>::new(Arc::new(records));
+        let res = resolver
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(resolver.stats().duplicates_suppressed(), 1);
+    }
+
+    #[test]
+    fn record_hash_matches_eip_labels() {
+        for (label, record) in EIP_RECORDS {
+            if let Some(label) = label {
+                assert_eq!(record_hash(record).as_str(), *label);
+            }
+        }
+    }
+
+    #[test]
+    fn content_hash_extends_label() {
+        for (label, record) in EIP_RECORDS {
+            if let Some(label) = label {
+                let hash = content_hash(record);
+                assert_eq!(BASE32_NOPAD.encode(&hash[..16]), *label);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dedup_modes() {
+        let versions = (1..=3)
+            .map(|seq| {
+                test_enr(1, |b| {
+                    b.seq(seq);
+                })
+                .to_base64()
+            })
+            .collect::<Vec<_>>();
+
+        let mut records = test_tree(
+            "a",
+            &[
+                versions[0].clone(),
+                versions[0].clone(),
+                versions[1].clone(),
+            ],
+        );
+        let link = test_label("LINK");
+        records.insert("LINKROOT.a".to_string(), format!("enrtree-branch:{}", link));
+        records.insert(format!("{}.a", link), test_link(2, "b"));
+        let mut remote = test_tree("b", &[versions[2].clone()]);
+        sign_test_tree(&mut remote, "b", 2);
+        records.extend(remote);
+        let records = Arc::new(records);
+
+        for (mode, expected, suppressed) in &[
+            (DedupMode::Off, vec![3, 1, 1, 2], 0),
+            (DedupMode::ExactDuplicatesOnly, vec![3, 1, 2], 1),
+            // The newest version comes from the linked tree and hides all three local ones.
+            (DedupMode::ByNodeIdKeepNewest, vec![3], 3),
+        ] {
+            let mut resolver = Resolver::<_, SigningKey>::new(records.clone());
+            resolver.with_dedup_mode(*mode);
+            let mut res = resolver
+                .query("a", None)
+                .map(|record| record.map(|record| record.seq()))
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .unwrap();
+            res[1..].sort_unstable();
+            assert_eq!(&res, expected, "{:?}", mode);
+            assert_eq!(
+                resolver.stats().duplicates_suppressed(),
+                *suppressed,
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn follow_inline_links() {
+        let local = test_enr(1, |_| {}).to_base64();
+        let remote = test_enr(2, |_| {}).to_base64();
+        let expected = hashset![local.clone(), remote.clone()];
+        let mut records = test_tree("a", &[local, test_link(2, "b")]);
+        let mut remote_tree = test_tree("b", &[remote]);
+        sign_test_tree(&mut remote_tree, "b", 2);
+        records.extend(remote_tree);
+        let records = Arc::new(records);
+
+        let err = Resolver::<_, SigningKey>::new(records.clone())
+            .query("a", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<WrongRecordKind>().is_some());
+
+        let res = Resolver::<_, SigningKey>::new(records)
+            .with_follow_inline_links(true)
+            .query("a", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res, expected);
+    }
+
+    #[tokio::test]
+    async fn nested_link_branches() {
+        let mut records = test_tree("a", &[]);
+        let (mid, link_b, link_c, link_d) = (
+            test_label("MID"),
+            test_label("LINKB"),
+            test_label("LINKC"),
+            test_label("LINKD"),
+        );
+        records.insert(
+            "LINKROOT.a".to_string(),
+            format!("enrtree-branch:{},{}", mid, link_d),
+        );
+        records.insert(
+            format!("{}.a", mid),
+            format!("enrtree-branch:{},{}", link_b, link_c),
+        );
+        let mut expected = HashSet::new();
+        for (seed, (label, domain)) in (2..).zip(&[(link_b, "b"), (link_c, "c"), (link_d, "d")]) {
+            records.insert(format!("{}.a", label), test_link(seed, domain));
+            let remote = test_enr(seed, |_| {}).to_base64();
+            expected.insert(remote.clone());
+            let mut remote_tree = test_tree(domain, &[remote]);
+            sign_test_tree(&mut remote_tree, domain, seed);
+            records.extend(remote_tree);
+        }
+
+        let res = Resolver::<_, SigningKey>::new(Arc::new(records))
+            .query("a", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res, expected);
+    }
+
+    #[tokio::test]
+    async fn max_links() {
+        let mut records = test_tree("a", &[]);
+        let links = (2..=5)
+            .map(|seed| {
+                let domain = format!("r{}", seed);
+                let label = test_label(&format!("LINK{}", seed));
+                records.insert(format!("{}.a", label), test_link(seed, &domain));
+                let mut remote_tree = test_tree(&domain, &[test_enr(seed, |_| {}).to_base64()]);
+                sign_test_tree(&mut remote_tree, &domain, seed);
+                records.extend(remote_tree);
+                label.to_string()
+            })
+            .collect::<Vec<_>>();
+        records.insert(
+            "LINKROOT.a".to_string(),
+            format!("enrtree-branch:{}", links.join(",")),
+        );
+        let backend = Arc::new(MockBackend::new(records));
+
+        let res = Resolver::<_, SigningKey>::new(backend.clone())
+            .with_max_links(2)
+            .query("a", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        // Root, both branches and four links of "a", then root, both branches and the ENR of
+        // each remote tree walked.
+        assert_eq!(backend.lookups(), 7 + 2 * 4);
+    }
+
+    #[tokio::test]
+    async fn self_link_skipped() {
+        let local = test_enr(1, |_| {});
+        let remote = test_enr(2, |_| {});
+        let mut records = test_tree("a", &[local.to_base64()]);
+        sign_test_tree(&mut records, "a", 1);
+        let (own, other) = (test_label("LINK1"), test_label("LINK2"));
+        records.insert(
+            "LINKROOT.a".to_string(),
+            format!("enrtree-branch:{},{}", own, other),
+        );
+        records.insert(format!("{}.a", own), test_link(1, "a"));
+        records.insert(format!("{}.a", other), test_link(2, "b"));
+        let mut remote_tree = test_tree("b", &[remote.to_base64()]);
+        sign_test_tree(&mut remote_tree, "b", 2);
+        records.extend(remote_tree);
+        let backend = Arc::new(MockBackend::new(records));
+
+        let res = Resolver::<_, SigningKey>::new(backend.clone())
+            .with_max_links(4)
+            .query("a", Some(test_key(1).public()))
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap()
+            .iter()
+            .map(Enr::to_base64)
+            .collect::<HashSet<_>>();
+        assert_eq!(res, hashset![local.to_base64(), remote.to_base64()]);
+        // Root, both branches, both links and the ENR of "a", then the tree of "b" once.
+        assert_eq!(backend.lookups(), 6 + 4);
+    }
+
+    /// Memory backend whose records can be replaced between queries.
+    #[derive(Default)]
+    struct MutableBackend(std::sync::Mutex<HashMap<String, String>>);
+
+    #[async_trait]
+    impl Backend for MutableBackend {
+        async fn get_record(&self, fqdn: String) -> anyhow::Result<Option<String>> {
+            Ok(self.0.lock().unwrap().get(&fqdn).cloned())
+        }
+    }
+
+    #[tokio::test]
+    async fn query_with_origin() {
+        let remote = test_enr(2, |_| {});
+        let mut records = test_tree("a", &[]);
+        let link = test_label("LINK");
+        records.insert("LINKROOT.a".to_string(), format!("enrtree-branch:{}", link));
+        records.insert(format!("{}.a", link), test_link(2, "b"));
+        records.extend(test_tree("b", &[remote.to_base64()]));
+        let base = "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=5";
+        let sig = test_key(2).sign_v4(base.as_bytes()).unwrap();
+        records.insert(
+            "b".to_string(),
+            format!("{} sig={}", base, BASE64URL_NOPAD.encode(&sig)),
+        );
+        let backend = Arc::new(MutableBackend::default());
+        let resolver = Resolver::<_, SigningKey>::new(backend.clone());
+
+        for (seq, local) in [(1, test_enr(1, |_| {})), (2, test_enr(3, |_| {}))] {
+            let leaf = test_label("LOCAL");
+            records.insert(
+                "a".to_string(),
+                format!("enrtree-root:v1 e=ENRROOT l=LINKROOT seq={} sig=", seq),
+            );
+            records.insert("ENRROOT.a".to_string(), format!("enrtree-branch:{}", leaf));
+            records.insert(format!("{}.a", leaf), local.to_base64());
+            *backend.0.lock().unwrap() = records.clone();
+
+            let found = resolver
+                .query_with_origin("a", None)
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(record, origin)| (record.to_base64(), origin))
+                .collect::<HashMap<_, _>>();
+            assert_eq!(
+                found,
+                hashmap! {
+                    local.to_base64() => Origin { domain: "a".to_string(), sequence: Some(seq) },
+                    remote.to_base64() => Origin { domain: "b".to_string(), sequence: Some(5) },
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_grouped() {
+        let local = test_enr(1, |_| {});
+        let remote = test_enr(2, |_| {});
+        let mut records = test_tree("a", &[local.to_base64()]);
+        let link = test_label("LINK");
+        records.insert("LINKROOT.a".to_string(), format!("enrtree-branch:{}", link));
+        records.insert(format!("{}.a", link), test_link(2, "b"));
+        let mut remote_tree = test_tree("b", &[remote.to_base64()]);
+        sign_test_tree(&mut remote_tree, "b", 2);
+        records.extend(remote_tree);
+
+        let groups = Resolver::<_, SigningKey>::new(Arc::new(records))
+            .resolve_grouped("a", None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(domain, enrs)| (domain, enrs.iter().map(Enr::to_base64).collect::<Vec<_>>()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            groups,
+            hashmap! {
+                "a".to_string() => vec![local.to_base64()],
+                "b".to_string() => vec![remote.to_base64()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn required_entries() {
+        let opstack = test_enr(1, |b| {
+            b.add_value("opstack", &[1, 2]);
+        });
+        let opstack_empty = test_enr(2, |b| {
+            b.add_value("opstack", &[]);
+        });
+        let eth2 = test_enr(3, |b| {
+            b.add_value("eth2", &[0xaa, 0xbb]);
+        });
+        let other_eth2 = test_enr(4, |b| {
+            b.add_value("eth2", &[0xcc]);
+        });
+        let bare = test_enr(5, |_| {});
+
+        let data = Arc::new(test_tree(
+            "n",
+            &[&opstack, &opstack_empty, &eth2, &other_eth2, &bare]
+                .iter()
+                .map(|enr| enr.to_base64())
+                .collect::<Vec<_>>(),
+        ));
+
+        assert!(EnrRequirement::has_key("opstack").matches(&opstack_empty));
+        assert!(EnrRequirement::has_value("opstack", vec![0x80]).matches(&opstack_empty));
+        assert!(!EnrRequirement::has_value("opstack", vec![]).matches(&opstack_empty));
+        assert!(!EnrRequirement::has_key("opstack").matches(&bare));
+        assert!(!EnrRequirement::any_of(vec![]).matches(&bare));
+
+        for (requirements, expected) in &[
+            (
+                vec![],
+                vec![&opstack, &opstack_empty, &eth2, &other_eth2, &bare],
+            ),
+            (
+                vec![EnrRequirement::has_key("opstack")],
+                vec![&opstack, &opstack_empty],
+            ),
+            (
+                vec![EnrRequirement::has_value("eth2", vec![0x82, 0xaa, 0xbb])],
+                vec![&eth2],
+            ),
+            (
+                vec![
+                    EnrRequirement::has_key("opstack"),
+                    EnrRequirement::has_value("opstack", vec![0x80]),
+                ],
+                vec![&opstack_empty],
+            ),
+            (
+                vec![EnrRequirement::any_of(vec![
+                    EnrRequirement::has_key("opstack"),
+                    EnrRequirement::has_value("eth2", vec![0x82, 0xaa, 0xbb]),
+                ])],
+                vec![&opstack, &opstack_empty, &eth2],
+            ),
+        ] {
+            let res = Resolver::<_, SigningKey>::new(data.clone())
+                .with_required_entries(requirements.clone())
+                .query("n", None)
+                .map(|res| res.map(|enr| enr.to_base64()))
+                .collect::<Result<HashSet<_>, _>>()
+                .await
+                .unwrap();
+            assert_eq!(
+                res,
+                expected.iter().map(|enr| enr.to_base64()).collect(),
+                "{:?}",
+                requirements
+            );
+        }
+    }
+
+    #[test]
+    fn record_too_long() {
+        let s = format!("enrtree-branch:{}", "A".repeat(MAX_RECORD_LEN));
+        let err = s.parse::<DnsRecord<SigningKey>>().unwrap_err();
+        assert_eq!(err.downcast_ref::<RecordTooLong>().unwrap().0, s.len());
+    }
+
+    #[test]
+    fn short_branch_child() {
+        let err = format!("enrtree-branch:{},LEAF0", test_label("A"))
+            .parse::<DnsRecord<SigningKey>>()
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidHashLength>().unwrap().0, "LEAF0");
+    }
+
+    #[tokio::test]
+    async fn strict_branches() {
+        let (a, b) = (test_label("A"), test_label("B"));
+        let lenient = format!("enrtree-branch:{},{},", a, b)
+            .parse::<DnsRecord<SigningKey>>()
+            .unwrap();
+        assert_eq!(
+            lenient.try_into_branch().unwrap(),
+            hashset![a.parse().unwrap(), b.parse().unwrap()]
+        );
+        assert!(
+            DnsRecord::<SigningKey>::parse_strict(&format!("enrtree-branch:{},{},", a, b)).is_err()
+        );
+        assert!(
+            DnsRecord::<SigningKey>::parse_strict(&format!("enrtree-branch:{},,{}", a, b)).is_err()
+        );
+        assert_eq!(
+            DnsRecord::<SigningKey>::parse_strict("enrtree-branch:")
+                .unwrap()
+                .try_into_branch()
+                .unwrap(),
+            HashSet::new()
+        );
+
+        let mut records = test_tree("n", &[EIP_ENR.to_string()]);
+        records.insert(
+            "ENRROOT.n".to_string(),
+            format!("enrtree-branch:{},", test_label("LEAF0")),
+        );
+        let records = Arc::new(records);
+
+        let res = Resolver::<_, SigningKey>::new(records.clone())
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+
+        assert!(Resolver::<_, SigningKey>::new(records)
+            .with_strict_branches(true)
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn query_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("enrs.txt");
+
+        let written = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )))
+        .query_to_file(EIP_DOMAIN, None, &path)
+        .await
+        .unwrap();
+        assert_eq!(written, 3);
+
+        let loaded = load_enrs_from_file::<SigningKey>(&path)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|enr| enr.to_base64())
+            .collect::<HashSet<_>>();
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded.contains(EIP_ENR));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probe() {
+        let backend = wide_test_backend();
+        let resolver = Resolver::<_, SigningKey>::new(backend.clone());
+        let record = resolver.probe("n", None).await.unwrap();
+        assert_eq!(record.to_base64(), EIP_ENR);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(backend.lookups(), 14);
+
+        assert!(
+            Resolver::<_, SigningKey>::new(Arc::new(test_tree("n", &[])))
+                .probe("n", None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "eth2")]
+    #[tokio::test]
+    async fn eth2_filters() {
+        use crate::{eth2::Eth2Info, filters};
+
+        const ALTAIR: [u8; 4] = [0xaf, 0xca, 0xab, 0xa0];
+        let eth2 = |digest: &str, attnets: &str| {
+            let eth2 = hex::decode(format!("{}02000000ffffffffffffffff", digest)).unwrap();
+            let attnets = hex::decode(attnets).unwrap();
+            move |b: &mut EnrBuilder<SigningKey>| {
+                b.add_value("eth2", &eth2)
+                    .add_value("attnets", &attnets)
+                    .add_value("syncnets", &[0x0f]);
+            }
+        };
+
+        let full = test_enr(1, eth2("afcaaba0", "ffffffffffffffff"));
+        let sparse = test_enr(2, eth2("afcaaba0", "0300000000000000"));
+        let other_fork = test_enr(3, eth2("4a26c58b", "ffffffffffffffff"));
+        let bad_attnets = test_enr(4, eth2("afcaaba0", "ffffffffffffff"));
+        let bad_eth2 = test_enr(5, |b| {
+            b.add_value("eth2", &[0xaf, 0xca, 0xab]);
+        });
+
+        let info = Eth2Info::from_enr(&full).unwrap();
+        assert_eq!(
+            info,
+            Eth2Info {
+                fork_digest: ALTAIR,
+                next_fork_version: [2, 0, 0, 0],
+                next_fork_epoch: u64::MAX,
+                attnets: Some([0xff; 8]),
+                syncnets: Some(0x0f),
+            }
+        );
+        assert_eq!(info.attnets_count(), Some(64));
+        assert_eq!(
+            Eth2Info::from_enr(&sparse).unwrap().attnets_count(),
+            Some(2)
+        );
+        assert_eq!(Eth2Info::from_enr(&bad_attnets).unwrap().attnets, None);
+        assert_eq!(Eth2Info::from_enr(&bad_eth2), None);
+        assert_eq!(Eth2Info::from_enr(&test_enr(6, |_| {})), None);
+
+        let data = Arc::new(test_tree(
+            "n",
+            &[&full, &sparse, &other_fork, &bad_attnets, &bad_eth2]
+                .iter()
+                .map(|enr| enr.to_base64())
+                .collect::<Vec<_>>(),
+        ));
+        let res = Resolver::<_, SigningKey>::new(data)
+            .with_filter(filters::eth2_fork_digest(ALTAIR))
+            .with_filter(filters::min_attnets(2))
+            .query("n", None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res, hashset![full.to_base64(), sparse.to_base64()]);
+    }
+
+    #[test]
+    fn record_hash_set() {
+        let records = EIP_RECORDS
+            .iter()
+            .chain(EIP_RECORDS)
+            .map(|(_, text)| text.parse::<DnsRecord<SigningKey>>().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(records.len(), EIP_RECORDS.len());
+
+        let (a, b) = (test_label("A"), test_label("B"));
+        let branch = |children: &[&str]| {
+            format!("enrtree-branch:{}", children.join(","))
+                .parse::<DnsRecord<SigningKey>>()
+                .unwrap()
+        };
+        assert_eq!(branch(&[&a, &b]), branch(&[&b, &a]));
+        assert_eq!(hashset![branch(&[&a, &b]), branch(&[&b, &a])].len(), 1);
+    }
+
+    #[test]
+    fn from_concatenated() {
+        let (head, tail) = EIP_ENR.split_at(40);
+        let record = DnsRecord::<SigningKey>::from_concatenated(&[head, tail]).unwrap();
+        assert_eq!(record.try_into_enr().unwrap().to_base64(), EIP_ENR);
+
+        assert!(DnsRecord::<SigningKey>::from_concatenated(&[head]).is_err());
+    }
+
+    #[tokio::test]
+    async fn prefetch() {
+        let backend = Arc::new(MockBackend::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )));
+        let count = Resolver::<_, SigningKey>::new(backend.clone())
+            .prefetch(EIP_DOMAIN, None)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(backend.lookups(), EIP_RECORDS.len() + 1);
+    }
+
+    #[tokio::test]
+    async fn sorted_output() {
+        let leaves = (1..=4)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let records = test_tree("n", &leaves);
+        let mut runs = vec![];
+        for slow in 0..leaves.len() {
+            let backend = MockBackend::new(records.clone()).with_delay_for(
+                &format!("{}.n", test_label(&format!("LEAF{}", slow))),
+                Duration::from_millis(50),
+            );
+            let res = Resolver::<_, SigningKey>::new(Arc::new(backend))
+                .with_sorted_output(true)
+                .query("n", None)
+                .map(|res| res.map(|enr| enr.to_base64()))
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .unwrap();
+            runs.push(res);
+        }
+
+        let mut expected = leaves;
+        expected.sort_by_key(|leaf| record_hash(leaf));
+        for run in runs {
+            assert_eq!(run, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_addresses() {
+        let v4 = IpAddr::from([203, 0, 113, 1]);
+        let v6 = IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]);
+        let leaves = [
+            test_enr(1, |b| {
+                b.ip(v4);
+            }),
+            test_enr(2, |b| {
+                b.ip(v4).ip(v6);
+            }),
+            test_enr(3, |_| {}),
+        ];
+        let addresses = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &leaves.iter().map(Enr::to_base64).collect::<Vec<_>>(),
+        )))
+        .resolve_addresses("n", None)
+        .await
+        .unwrap();
+        assert_eq!(addresses, hashset![v4, v6]);
+    }
+
+    #[tokio::test]
+    async fn shared_query_drop_oldest() {
+        let leaves = (1..=5)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let query =
+            Resolver::<_, SigningKey>::new(Arc::new(test_tree("n", &leaves))).query("n", None);
+
+        let mut fast = query.into_shared(2);
+        let mut slow = fast.clone();
+
+        let mut all = HashSet::new();
+        while let Some(record) = fast.next().await {
+            all.insert(record.unwrap().to_base64());
+        }
+        assert_eq!(all, leaves.iter().cloned().collect());
+        assert_eq!(fast.skipped(), 0);
+
+        let mut rest = vec![];
+        while let Some(record) = slow.next().await {
+            rest.push(record.unwrap().to_base64());
+        }
+        assert_eq!(rest.len(), 2);
+        assert!(rest.iter().all(|record| all.contains(record)));
+        assert_eq!(slow.skipped(), 3);
+    }
+
+    #[tokio::test]
+    async fn shared_query_backpressure() {
+        let leaves = (1..=5)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let query =
+            Resolver::<_, SigningKey>::new(Arc::new(test_tree("n", &leaves))).query("n", None);
+
+        let mut fast = query.into_shared_with(1, LagPolicy::Backpressure);
+        let mut slow = fast.clone();
+
+        let first = fast.next().await.unwrap().unwrap();
+        // The buffer is full until the slow consumer takes the first ENR.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), fast.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(slow.next().await.unwrap().unwrap(), first);
+
+        let (fast, slow) = tokio::join!(
+            fast.map(|record| record.unwrap().to_base64())
+                .collect::<Vec<_>>(),
+            async {
+                let mut records = vec![];
+                while let Some(record) = slow.next().await {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    records.push(record.unwrap().to_base64());
+                }
+                records
+            }
+        );
+        assert_eq!(fast.len(), 4);
+        assert_eq!(fast, slow);
+    }
+
+    #[tokio::test]
+    async fn fetch_root() {
+        let mut records = test_tree("n", &[]);
+        sign_test_tree(&mut records, "n", 1);
+        let published = records["n"].clone();
+
+        let (text, root) = Resolver::<_, SigningKey>::new(Arc::new(records))
+            .fetch_root("n", Some(test_key(1).public()))
+            .await
+            .unwrap();
+        assert_eq!(text, published);
+        assert_eq!(root.to_string(), text);
+
+        let reparsed = DnsRecord::<SigningKey>::from_str(&text)
+            .unwrap()
+            .try_into_root()
+            .unwrap();
+        assert_eq!(reparsed.to_string(), root.to_string());
+        assert_eq!(reparsed.signature_bytes(), root.signature_bytes());
+        reparsed
+            .verify::<SigningKey>(&test_key(1).public())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_backend_hierarchical_fallback() {
+        let mut backend = MemoryBackend::new(hashmap! {
+            "example.org".to_string() => "apex".to_string(),
+            "c.example.org".to_string() => "c".to_string(),
+        });
+        assert_eq!(
+            backend
+                .get_record("c.example.org".to_string())
+                .await
+                .unwrap(),
+            Some("c".to_string())
+        );
+        assert_eq!(
+            backend
+                .get_record("a.b.c.example.org".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+
+        backend.with_hierarchical_fallback(true);
+        assert_eq!(
+            backend
+                .get_record("a.b.c.example.org".to_string())
+                .await
+                .unwrap(),
+            Some("c".to_string())
+        );
+        assert_eq!(
+            backend
+                .get_record("x.example.org".to_string())
+                .await
+                .unwrap(),
+            Some("apex".to_string())
+        );
+        assert_eq!(
+            backend
+                .get_record("x.example.com".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn query_raw() {
+        let resolver = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )));
+        let raw = resolver
+            .query_raw(EIP_DOMAIN, None)
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        let decoded = resolver
+            .query(EIP_DOMAIN, None)
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(raw.len(), 3);
+        assert_eq!(raw, decoded);
+
+        // Labels of test trees are not hashes of their records.
+        let err = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &[test_enr(1, |_| {}).to_base64()],
+        )))
+        .query_raw("n", None)
+        .try_next()
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HashMismatch>().unwrap().fqdn,
+            "LINKROOT.n"
+        );
+    }
+
+    #[tokio::test]
+    async fn query_raw_walk() {
+        fn signed_root(enr_root: Base32Hash, link_root: Base32Hash, seed: u8) -> String {
+            let base = format!("enrtree-root:v1 e={} l={} seq=1", enr_root, link_root);
+            let sig = test_key(seed).sign_v4(base.as_bytes()).unwrap();
+            format!("{} sig={}", base, BASE64URL_NOPAD.encode(&sig))
+        }
+
+        let mut records = HashMap::new();
+        let mut publish = |domain: &str, text: String| {
+            let label = record_hash(&text);
+            records.insert(format!("{}.{}", label, domain), text);
+            label
+        };
+
+        let linked = publish("m", test_enr(4, |_| {}).to_base64());
+        let m_enr_root = publish("m", format!("enrtree-branch:{}", linked));
+        let m_link_root = publish("m", "enrtree-branch:".to_string());
+
+        let leaves = (1..=3)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let padding = (4 - (leaves[0].len() - ENR_PREFIX.len()) % 4) % 4;
+        let enrs = [
+            publish("n", format!("{}{}", leaves[0], "=".repeat(padding))),
+            publish("n", leaves[1].replace('-', "+").replace('_', "/")),
+            publish("n", leaves[2].clone()),
+        ];
+        let link = publish("n", test_link(2, "m"));
+        let n_enr_root = publish(
+            "n",
+            format!("enrtree-branch:{},{},{}", enrs[0], enrs[1], enrs[2]),
+        );
+        let n_link_root = publish("n", format!("enrtree-branch:{}", link));
+        records.insert("m".to_string(), signed_root(m_enr_root, m_link_root, 2));
+        records.insert("n".to_string(), signed_root(n_enr_root, n_link_root, 1));
+
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(records));
+        let raw = resolver
+            .query_raw("n", Some(test_key(1).public()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        let decoded = resolver
+            .query("n", Some(test_key(1).public()))
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(raw.len(), 4);
+        assert_eq!(raw, decoded);
+
+        // Both trees have 7 records besides their 4 ENRs.
+        for (limit, fits) in [(10, false), (11, true)] {
+            resolver.with_max_records(limit);
+            let res = resolver
+                .query_raw("n", None)
+                .collect::<Result<Vec<_>, _>>()
+                .await;
+            match res {
+                Ok(raw) => assert!(fits && raw.len() == 4),
+                Err(e) => assert!(!fits && e.is::<TreeTooLarge>()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_into() {
+        let a = test_enr(1, |_| {});
+        let b = test_enr(2, |_| {});
+        let b_new = test_enr(2, |b| {
+            b.seq(2);
+        });
+        let nodes = Arc::new(Mutex::new(HashMap::new()));
+
+        let counts = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &[a.to_base64(), b.to_base64()],
+        )))
+        .sync_into("n", None, &nodes)
+        .await
+        .unwrap();
+        assert_eq!(
+            counts,
+            SyncCounts {
+                added: 2,
+                updated: 0
+            }
+        );
+
+        let counts = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &[a.to_base64(), b_new.to_base64()],
+        )))
+        .sync_into("n", None, &nodes)
+        .await
+        .unwrap();
+        assert_eq!(
+            counts,
+            SyncCounts {
+                added: 0,
+                updated: 1
+            }
+        );
+
+        let nodes = nodes.lock().unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[&a.node_id()], a);
+        assert_eq!(nodes[&b.node_id()], b_new);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn query_to_json() {
+        let v4 = test_enr(1, |b| {
+            b.ip("203.0.113.1".parse().unwrap()).udp(30303);
+        });
+        let v6 = test_enr(2, |b| {
+            b.ip("2001:db8::1".parse().unwrap()).tcp6(30304);
+        });
+        let json = Resolver::<_, SigningKey>::new(Arc::new(test_tree(
+            "n",
+            &[v4.to_base64(), v6.to_base64()],
+        )))
+        .query_to_json("n", None)
+        .await
+        .unwrap();
+
+        let mut expected = vec![
+            serde_json::json!({
+                "enr": v4.to_base64(),
+                "nodeId": hex::encode(v4.node_id().raw()),
+                "ip": "203.0.113.1",
+                "port": 30303,
+            }),
+            serde_json::json!({
+                "enr": v6.to_base64(),
+                "nodeId": hex::encode(v6.node_id().raw()),
+                "ip": "2001:db8::1",
+                "port": 30304,
+            }),
+        ];
+        let mut found = json.as_array().unwrap().clone();
+        let key = |v: &serde_json::Value| v["enr"].as_str().unwrap().to_string();
+        expected.sort_by_key(key);
+        found.sort_by_key(key);
+        assert_eq!(found, expected);
+    }
+
+    #[tokio::test]
+    async fn count_enrs() {
+        let skipped = EIP_RECORDS[3]
+            .1
+            .parse::<Enr<SigningKey>>()
+            .unwrap()
+            .node_id();
+        let count = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )))
+        .with_filter(move |record| record.node_id() != skipped)
+        .count_enrs(EIP_DOMAIN, None)
+        .await
+        .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn unsupported_enr_scheme() {
+        let mut rlp = rlp::RlpStream::new_list(4);
+        rlp.append(&vec![0_u8; 64])
+            .append(&1_u64)
+            .append(&"id")
+            .append(&"v9");
+        let unsupported = format!("enr:{}", BASE64URL_NOPAD.encode(&rlp.out()));
+
+        let err = DnsRecord::<SigningKey>::from_str(&unsupported).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(InvalidEnr::UnsupportedScheme(scheme)) if scheme == "v9"
+        ));
+        let err = DnsRecord::<SigningKey>::from_str("enr:-----").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(InvalidEnr::Corrupt(_))));
+
+        let data = Arc::new(test_tree("n", &[EIP_ENR.to_string(), unsupported]));
+        assert!(Resolver::<_, SigningKey>::new(data.clone())
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .is_err());
+
+        let res = Resolver::<_, SigningKey>::new(data)
+            .with_skip_unsupported_enrs(true)
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+    }
+
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test]
+    async fn authoritative_backend() {
+        use trust_dns_resolver::{
+            config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+            proto::rr::{rdata::TXT, Name, RData, Record},
+            TokioAsyncResolver,
+        };
+
+        let records = test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS);
+        let authoritative = spawn_dns_server(records.clone()).await;
+        let ns = Name::from_ascii("ns.mynodes.org.").unwrap();
+        let recursive = spawn_dns_server_with_records(vec![
+            Record::from_rdata(
+                Name::from_ascii("mynodes.org.").unwrap(),
+                60,
+                RData::NS(ns.clone()),
+            ),
+            Record::from_rdata(ns, 60, RData::A([127, 0, 0, 1].into())),
+            Record::from_rdata(
+                Name::from_ascii("mynodes.org.").unwrap(),
+                60,
+                RData::TXT(TXT::new(vec![records[EIP_DOMAIN].clone()])),
+            ),
+        ])
+        .await;
+        let bootstrap = || {
+            TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(
+                    None,
+                    vec![],
+                    NameServerConfigGroup::from_ips_clear(
+                        &[recursive.ip()],
+                        recursive.port(),
+                        true,
+                    ),
+                ),
+                ResolverOpts::default(),
+            )
+            .unwrap()
+        };
+
+        // Only the authoritative server has the whole tree.
+        let mut backend = AuthoritativeBackend::new(bootstrap());
+        backend.with_port(authoritative.port());
+        let res = Resolver::<_, SigningKey>::new(Arc::new(backend))
+            .query(EIP_DOMAIN, None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+
+        // A silent name server makes lookups fall back to the recursive resolver.
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut backend = AuthoritativeBackend::new(bootstrap());
+        backend
+            .with_port(silent.local_addr().unwrap().port())
+            .with_timeout(Duration::from_millis(100));
+        assert_eq!(
+            backend.get_record(EIP_DOMAIN.to_string()).await.unwrap(),
+            Some(records[EIP_DOMAIN].clone())
+        );
+    }
+
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test(start_paused = true)]
+    async fn multiserver_timeout() {
+        use trust_dns_resolver::proto::rr::{rdata::TXT, Name, RData, Record};
+
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slow = spawn_slow_dns_server(
+            vec![Record::from_rdata(
+                Name::from_ascii("n.").unwrap(),
+                60,
+                RData::TXT(TXT::new(vec!["enrtree-branch:".to_string()])),
+            )],
+            Duration::from_secs(3),
+        )
+        .await;
+        let servers = vec![silent.local_addr().unwrap(), slow];
+        let backend = MultiserverTrustDnsBackend::new(servers.clone()).unwrap();
+
+        // The slow server answers after the default timeout.
+        assert!(backend.get_record("n".to_string()).await.is_err());
+
+        let started = Instant::now();
+        let backend =
+            MultiserverTrustDnsBackend::new_with_timeout(servers, Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            backend.get_record("n".to_string()).await.unwrap(),
+            Some("enrtree-branch:".to_string())
+        );
+        assert!(started.elapsed() >= Duration::from_secs(8));
+
+        assert!(MultiserverTrustDnsBackend::new(vec![]).is_err());
+    }
+
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test]
+    async fn trust_dns_joins_txt_strings() {
+        use trust_dns_resolver::{
+            config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+            proto::rr::{rdata::TXT, Name, RData, Record},
+            TokioAsyncResolver,
+        };
+
+        let addr = spawn_dns_server_with_records(vec![Record::from_rdata(
+            Name::from_ascii("n.").unwrap(),
+            60,
+            RData::TXT(TXT::new(vec![
+                "enrtree-root:v1 e=ENRROOT".to_string(),
+                " l=LINKROOT seq=1 sig=".to_string(),
+            ])),
+        )])
+        .await;
+        let resolver = TokioAsyncResolver::tokio(
+            ResolverConfig::from_parts(
+                None,
+                vec![],
+                NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+            ),
+            ResolverOpts::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            resolver.get_record("n".to_string()).await.unwrap().unwrap(),
+            "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 sig="
+        );
+    }
+
+    /// Serves TXT records over UDP on a local port, answering NXDOMAIN for unknown names.
+    #[cfg(feature = "trust-dns")]
+    async fn spawn_dns_server(records: HashMap<String, String>) -> std::net::SocketAddr {
+        use trust_dns_resolver::proto::rr::{rdata::TXT, Name, RData, Record};
+
+        spawn_dns_server_with_records(
+            records
+                .into_iter()
+                .map(|(name, txt)| {
+                    Record::from_rdata(
+                        Name::from_ascii(name).unwrap(),
+                        60,
+                        RData::TXT(TXT::new(vec![txt])),
+                    )
+                })
+                .collect(),
+        )
+        .await
+    }
+
+    /// Serves arbitrary records over UDP on a local port, answering NXDOMAIN for queries with
+    /// no matching record.
+    #[cfg(feature = "trust-dns")]
+    async fn spawn_dns_server_with_records(
+        records: Vec<trust_dns_resolver::proto::rr::Record>,
+    ) -> std::net::SocketAddr {
+        spawn_slow_dns_server(records, Duration::from_secs(0)).await
+    }
+
+    /// Like `spawn_dns_server_with_records`, but waits `delay` before answering each query.
+    #[cfg(feature = "trust-dns")]
+    async fn spawn_slow_dns_server(
+        records: Vec<trust_dns_resolver::proto::rr::Record>,
+        delay: Duration,
+    ) -> std::net::SocketAddr {
+        use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(request.op_code())
+                    .set_recursion_desired(request.recursion_desired())
+                    .set_recursion_available(true);
+                for query in request.queries() {
+                    response.add_query(query.clone());
+                    let mut answers = records
+                        .iter()
+                        .filter(|record| {
+                            record.record_type() == query.query_type()
+                                && record.name().to_lowercase() == query.name().to_lowercase()
+                        })
+                        .peekable();
+                    if answers.peek().is_none() {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                    for record in answers {
+                        let mut record = record.clone();
+                        record.set_name(query.name().clone());
+                        response.add_answer(record);
+                    }
+                }
+                if delay > Duration::from_secs(0) {
+                    tokio::time::sleep(delay).await;
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test]
+    async fn shared_trust_dns_resolver() {
+        use trust_dns_resolver::{
+            config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+            TokioAsyncResolver,
+        };
+
+        let addr = spawn_dns_server(test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS)).await;
+        let resolver = Arc::new(
+            TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(
+                    None,
+                    vec![],
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+                ),
+                ResolverOpts::default(),
+            )
+            .unwrap(),
+        );
+
+        let (a, b) = tokio::join!(
+            Resolver::<_, SigningKey>::from_shared_resolver(&resolver)
+                .query(EIP_DOMAIN, None)
+                .collect::<Result<Vec<_>, _>>(),
+            Resolver::<_, SigningKey>::from_shared_resolver(&resolver)
+                .query(EIP_DOMAIN, None)
+                .collect::<Result<Vec<_>, _>>(),
+        );
+        assert_eq!(a.unwrap().len(), 3);
+        assert_eq!(b.unwrap().len(), 3);
+    }
+
+    #[cfg(feature = "k256")]
+    #[test]
+    fn increment_sequence() {
+        let key = test_key(1);
+        let root = DnsRecord::<SigningKey>::from_str(EIP_RECORDS[0].1)
+            .unwrap()
+            .try_into_root()
+            .unwrap();
+        let enr_root = "AAAAAAAAAAAAAAAAAAAAAAAAAA".parse().unwrap();
+        let link_root = "BBBBBBBBBBBBBBBBBBBBBBBBBB".parse().unwrap();
+
+        let next = root.increment_sequence(enr_root, link_root, &key).unwrap();
+        assert_eq!(next.enr_root, enr_root);
+        assert_eq!(next.link_root, link_root);
+        assert_eq!(next.sequence, 2);
+        assert!(next.signature_bytes().is_some());
+        assert_eq!(next.recover_key().unwrap(), key.public());
+
+        let published = next
+            .to_string()
+            .parse::<DnsRecord<SigningKey>>()
+            .unwrap()
+            .try_into_root()
+            .unwrap();
+        assert_eq!(published.recover_key().unwrap(), key.public());
+        published.verify::<SigningKey>(&key.public()).unwrap();
+        assert!(published
+            .verify::<SigningKey>(&test_key(2).public())
+            .is_err());
+    }
+
+    #[test]
+    fn root_signature_parts() {
+        let root = DnsRecord::<SigningKey>::from_str(EIP_RECORDS[0].1)
+            .unwrap()
+            .try_into_root()
+            .unwrap();
+        let signature = root.signature_bytes().unwrap();
+        assert_eq!(&signature[..], root.signature());
+        let copy = RootRecord::from_parts((*root).clone(), &signature).unwrap();
+        assert_eq!(copy.to_string(), root.to_string());
+
+        let mut bad = signature;
+        bad[64] = 27;
+        let err = RootRecord::from_parts((*root).clone(), &bad).unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidRecoveryId>().unwrap().0, 27);
+
+        let enr_root = "AAAAAAAAAAAAAAAAAAAAAAAAAA".parse().unwrap();
+        let link_root = "BBBBBBBBBBBBBBBBBBBBBBBBBB".parse().unwrap();
+        let base = UnsignedRoot::new(enr_root, link_root, 1);
+        assert_eq!(
+            base.to_string(),
+            format!("enrtree-root:v1 e={} l={} seq=1", enr_root, link_root)
+        );
+        let signature = test_key(1).sign_v4(base.to_string().as_bytes()).unwrap();
+        let published = DnsRecord::<SigningKey>::from_str(&format!(
+            "{} sig={}",
+            base,
+            BASE64URL_NOPAD.encode(&signature)
+        ))
+        .unwrap()
+        .try_into_root()
+        .unwrap();
+        assert!(published.signature_bytes().is_none());
+        assert_eq!(published.signature(), signature.as_slice());
+    }
+
+    #[cfg(feature = "k256")]
+    #[test]
+    fn root_ecdsa_parts() {
+        use k256::ecdsa::{recoverable, signature::DigestSigner};
+
+        let key = test_key(1);
+        let enr_root = "AAAAAAAAAAAAAAAAAAAAAAAAAA".parse().unwrap();
+        let link_root = "BBBBBBBBBBBBBBBBBBBBBBBBBB".parse().unwrap();
+        let base = UnsignedRoot::new(enr_root, link_root, 1);
+        let signature: recoverable::Signature =
+            key.sign_digest(Keccak256::new().chain(base.to_string()));
+        let signature = <[u8; 65]>::try_from(signature.as_ref()).unwrap();
+
+        let root = RootRecord::from_parts(base.clone(), &signature).unwrap();
+        assert_eq!(root.signature_bytes().unwrap(), signature);
+        root.verify::<SigningKey>(&key.public()).unwrap();
+        assert_eq!(root.recover_key().unwrap(), key.public());
+
+        let (ecdsa, recovery_id) = root.ecdsa_parts().unwrap();
+        assert_eq!(ecdsa.as_ref(), &signature[..64]);
+        assert_eq!(recovery_id, signature[64]);
+        let copy = RootRecord::from_ecdsa_parts(base.clone(), &ecdsa, recovery_id).unwrap();
+        assert_eq!(copy.to_string(), root.to_string());
+        assert!(RootRecord::from_ecdsa_parts(base, &ecdsa, 2)
+            .unwrap_err()
+            .is::<InvalidRecoveryId>());
+    }
+
+    #[test]
+    fn invalid_signature_format() {
+        let base = "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1";
+        let sig = test_key(1).sign_v4(base.as_bytes()).unwrap();
+        let root = DnsRecord::<SigningKey>::from_str(&format!(
+            "{} sig={}",
+            base,
+            BASE64URL_NOPAD.encode(&sig[..48])
+        ))
+        .unwrap()
+        .try_into_root()
+        .unwrap();
+
+        let err = root
+            .verify::<SigningKey>(&test_key(1).public())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidSignatureFormat>().unwrap().0, 48);
+    }
+
+    #[cfg(feature = "k256")]
+    #[test]
+    fn recover_root_key() {
+        use k256::ecdsa::{recoverable, signature::DigestSigner};
+
+        let base = DnsRecord::<SigningKey>::from_str(EIP_RECORDS[0].1)
+            .unwrap()
+            .try_into_root()
+            .unwrap()
+            .next_sequence(
+                "AAAAAAAAAAAAAAAAAAAAAAAAAA".parse().unwrap(),
+                "BBBBBBBBBBBBBBBBBBBBBBBBBB".parse().unwrap(),
+            );
+        let signature: recoverable::Signature =
+            test_key(1).sign_digest(Keccak256::new().chain(base.to_string()));
+        let signature = <[u8; 65]>::try_from(signature.as_ref()).unwrap();
+        let root = RootRecord::from_parts(base.clone(), &signature).unwrap();
+        assert_eq!(root.recover_key().unwrap(), test_key(1).public());
+
+        let signature = test_key(1).sign_v4(base.to_string().as_bytes()).unwrap();
+        let root = DnsRecord::<SigningKey>::from_str(&format!(
+            "{} sig={}",
+            base,
+            BASE64URL_NOPAD.encode(&signature)
+        ))
+        .unwrap()
+        .try_into_root()
+        .unwrap();
+        assert!(root.recover_key().is_err());
+    }
+
+    #[test]
+    fn root_extra_entries() {
+        let base = "enrtree-root:v1 e=ENRROOT l=LINKROOT seq=1 meta=testnet net=5";
+        let sig = test_key(1).sign_v4(base.as_bytes()).unwrap();
+        let text = format!("{} sig={}", base, BASE64URL_NOPAD.encode(&sig));
+
+        let root = DnsRecord::<SigningKey>::from_str(&text)
+            .unwrap()
+            .try_into_root()
+            .unwrap();
+        assert_eq!(
+            root.extra(),
+            &[
+                ("meta".to_string(), "testnet".to_string()),
+                ("net".to_string(), "5".to_string())
+            ]
+        );
+        assert_eq!(root.to_string(), text);
+        root.verify::<SigningKey>(&test_key(1).public()).unwrap();
+
+        assert!(DnsRecord::<SigningKey>::from_str(&format!("{} junk", text)).is_err());
+    }
+
+    #[test]
+    fn record_sub_parsers() {
+        assert_eq!(
+            parser::root_record_parser(" e=A l=B seq=3 sig=C ").unwrap(),
+            ("", vec![("e", "A"), ("l", "B"), ("seq", "3"), ("sig", "C")])
+        );
+        assert_eq!(
+            parser::root_entry_parser("seq=3 rest").unwrap(),
+            (" rest", ("seq", "3"))
+        );
+        assert!(parser::root_record_parser(" e=A junk").is_err());
+        assert!(parser::root_entry_parser("=A").is_err());
+
+        assert_eq!(
+            parser::link_record_parser("PUBKEY@nodes.example.org").unwrap(),
+            ("", ("PUBKEY", "nodes.example.org"))
+        );
+        assert!(parser::link_record_parser("PUBKEY").is_err());
+        assert!(parser::link_record_parser("@nodes.example.org").is_err());
+
+        assert_eq!(
+            parser::branch_record_parser("A,B,,C").unwrap(),
+            ("", vec!["A", "B", "", "C"])
+        );
+        assert_eq!(parser::branch_record_parser("").unwrap(), ("", vec![""]));
+        assert!(parser::branch_record_parser("A, B").is_err());
+
+        assert_eq!(
+            parser::enr_record_parser("-Iu4Q+/x=").unwrap(),
+            ("", "-Iu4Q+/x=")
+        );
+        assert!(parser::enr_record_parser("-Iu4Q!").is_err());
+
+        let err = parser::run(" e=A junk", parser::root_record_parser).unwrap_err();
+        assert!(err.to_string().contains("root record"));
+    }
+
+    #[tokio::test]
+    async fn collect_map() {
+        let old = test_enr(1, |b| {
+            b.seq(1);
+        });
+        let new = test_enr(1, |b| {
+            b.seq(2);
+        });
+        let other = test_enr(2, |_| {});
+        let mut data = test_tree(
+            "n",
+            &[&new, &old, &other]
+                .iter()
+                .map(|enr| enr.to_base64())
+                .collect::<Vec<_>>(),
+        );
+        data.insert("bad".to_string(), "enrtree-root:v1 bogus".to_string());
+
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(data));
+        resolver.with_dedup_mode(DedupMode::Off);
+
+        let nodes = resolver.collect_map("n", None, None).await.unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[&new.node_id()].seq(), 2);
+        assert!(nodes.contains_key(&other.node_id()));
+
+        assert_eq!(
+            resolver
+                .collect_map("n", None, Some(1))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(resolver.collect_map("bad", None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn collect_map_skips_failed_subtree() {
+        let healthy = (1..=3)
+            .map(|seed| test_enr(seed, |_| {}))
+            .collect::<Vec<_>>();
+        let mut leaves = healthy
+            .iter()
+            .map(|enr| enr.to_base64())
+            .collect::<Vec<_>>();
+        let broken = test_label("BROKEN");
+        leaves.insert(1, format!("enrtree-branch:{}", broken));
+        let mut data = test_tree("n", &leaves);
+        data.insert(format!("{}.n", broken), "enr:bogus".to_string());
+        let resolver = Resolver::<_, SigningKey>::new(Arc::new(data));
+
+        assert!(resolver
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .is_err());
+
+        let nodes = resolver.collect_map("n", None, None).await.unwrap();
+        assert_eq!(
+            nodes.keys().copied().collect::<HashSet<_>>(),
+            healthy.iter().map(|enr| enr.node_id()).collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn query_with_timestamp() {
+        let start = SystemTime::now();
+        let res = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )))
+        .query_with_timestamp(EIP_DOMAIN, None)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .unwrap();
+        let end = SystemTime::now();
+
+        assert_eq!(res.len(), 3);
+        for window in res.windows(2) {
+            assert!(window[0].1 <= window[1].1);
         }
+        assert!(res.iter().all(|(_, seen)| start <= *seen && *seen <= end));
+    }
+
+    #[tokio::test]
+    async fn raw_api() {
+        let backend = test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS);
+        let label = |i: usize| EIP_RECORDS[i].0.unwrap().parse::<Base32Hash>().unwrap();
+
+        let root = raw::fetch_root::<SigningKey>(&backend, EIP_DOMAIN, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.to_string(), EIP_RECORDS[0].1);
+
+        let link = raw::fetch_record::<SigningKey, SigningKey>(&backend, EIP_DOMAIN, label(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.record.kind(), RecordKind::Link);
+        raw::expect_kind(&link, None, RecordKind::Link).unwrap();
+        assert!(raw::expand_branch(&link.record).is_none());
+
+        let branch = raw::fetch_record::<SigningKey, SigningKey>(&backend, EIP_DOMAIN, label(2))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(branch.text, EIP_RECORDS[2].1);
+        raw::expect_kind(&branch, None, RecordKind::Enr).unwrap();
+        let children = raw::expand_branch(&branch.record).unwrap();
+        assert_eq!(children, (3..6).map(label).collect::<Vec<_>>());
+
+        let enr = raw::fetch_record::<SigningKey, SigningKey>(&backend, EIP_DOMAIN, children[0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(enr.fqdn, format!("{}.{}", children[0], EIP_DOMAIN));
+        assert_eq!(enr.record.kind(), RecordKind::Enr);
+        let err = raw::expect_kind(&enr, Some(branch.label), RecordKind::Link).unwrap_err();
+        assert_eq!(err.parent, Some(branch.label));
+
+        // Labels of test trees are not hashes of their records.
+        let mut records = test_tree("n", &[]);
+        sign_test_tree(&mut records, "n", 1);
+        let root = raw::fetch_root::<SigningKey>(&records, "n", Some(&test_key(1).public()))
+            .await
+            .unwrap()
+            .unwrap();
+        raw::verify_root::<SigningKey>(&root, &test_key(1).public()).unwrap();
+        assert!(raw::verify_root::<SigningKey>(&root, &test_key(2).public()).is_err());
+        let err = raw::fetch_record::<SigningKey, SigningKey>(&records, "n", root.enr_root)
+            .await
+            .unwrap_err();
+        assert!(err.is::<HashMismatch>());
+    }
+
+    #[tokio::test]
+    async fn query_with_diagnostics() {
+        let mut records = HashMap::new();
+        let mut publish = |text: String| {
+            let label = record_hash(&text);
+            records.insert(format!("{}.n", label), text);
+            label
+        };
+
+        let good = (1..=2)
+            .map(|seed| test_enr(seed, |_| {}).to_base64())
+            .collect::<Vec<_>>();
+        let mut labels = good.iter().cloned().map(&mut publish).collect::<Vec<_>>();
+        let tampered = publish(test_enr(3, |_| {}).to_base64());
+        let malformed = publish("enrtree-bogus:".to_string());
+        labels.extend([tampered, malformed]);
+        let enr_root = publish(format!("enrtree-branch:{}", labels.join(",")));
+        let link_root = publish("enrtree-branch:".to_string());
+        records.insert(
+            "n".to_string(),
+            format!("enrtree-root:v1 e={} l={} seq=1 sig=", enr_root, link_root),
+        );
+        records.insert(format!("{}.n", tampered), test_enr(4, |_| {}).to_base64());
+
+        let mut resolver = Resolver::<_, SigningKey>::new(Arc::new(records));
+
+        // Diagnostics alone do not enable hash checks.
+        let (enrs, diagnostics) = resolver.query_with_diagnostics("n", None);
+        assert_eq!(enrs.collect::<Result<Vec<_>, _>>().await.unwrap().len(), 3);
+        assert_eq!(diagnostics.collect::<Vec<_>>().await.len(), 1);
+
+        resolver.with_verify_hashes(true);
+        let (enrs, diagnostics) = resolver.query_with_diagnostics("n", None);
+        let enrs = enrs
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(enrs, good.into_iter().collect());
+
+        let diagnostics = diagnostics
+            .map(|diagnostic| (diagnostic.fqdn.clone(), diagnostic))
+            .collect::<HashMap<_, _>>()
+            .await;
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[&format!("{}.n", tampered)]
+            .error
+            .is::<HashMismatch>());
+        assert!(diagnostics[&format!("{}.n", malformed)]
+            .error
+            .to_string()
+            .contains("Invalid string"));
+    }
+
+    #[tokio::test]
+    async fn verify_then_resolve() {
+        let mut records = HashMap::new();
+        let mut publish = |domain: &str, text: String| {
+            let label = record_hash(&text);
+            records.insert(format!("{}.{}", label, domain), text);
+            label
+        };
+
+        let enrs = (1..=4)
+            .map(|seed| publish("n", test_enr(seed, |_| {}).to_base64()))
+            .collect::<Vec<_>>();
+        let left = publish("n", format!("enrtree-branch:{},{}", enrs[0], enrs[1]));
+        let right = publish("n", format!("enrtree-branch:{},{}", enrs[2], enrs[3]));
+        let enr_root = publish("n", format!("enrtree-branch:{},{}", left, right));
+        let link_root = publish("n", "enrtree-branch:".to_string());
+        records.insert(
+            "n".to_string(),
+            format!("enrtree-root:v1 e={} l={} seq=1 sig=", enr_root, link_root),
+        );
+
+        let res = Resolver::<_, SigningKey>::new(Arc::new(records.clone()))
+            .with_verify_then_resolve(true)
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 4);
+
+        records.insert(
+            format!("{}.n", right),
+            format!("enrtree-branch:{}", enrs[2]),
+        );
+        let mut s = Resolver::<_, SigningKey>::new(Arc::new(records))
+            .with_verify_then_resolve(true)
+            .query("n", None);
+        let err = s.try_next().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HashMismatch>().unwrap().fqdn,
+            format!("{}.n", right)
+        );
+    }
+
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test]
+    async fn tuned_resolver() {
+        use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig};
+
+        let opts = tuned_resolver_opts();
+        assert_eq!(opts.timeout, Duration::from_secs(3));
+        assert_eq!(opts.attempts, 2);
+        assert_eq!(opts.cache_size, 4096);
+        assert!(opts.edns0);
+        assert!(!opts.use_hosts_file);
+
+        let addr = spawn_dns_server(test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS)).await;
+        let resolver = super::tuned_resolver(ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+        ))
+        .unwrap();
+        let res = Resolver::<_, SigningKey>::new(Arc::new(resolver))
+            .query(EIP_DOMAIN, None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn enr_iterator() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stream = Resolver::<_, SigningKey>::new(Arc::new(test_records_to_hashmap(
+            EIP_DOMAIN,
+            EIP_RECORDS,
+        )))
+        .query(EIP_DOMAIN, None);
+        let res = EnrIterator::new(stream, runtime.handle().clone())
+            .map(|res| res.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            res,
+            EIP_RECORDS[3..]
+                .iter()
+                .map(|(_, text)| text.to_string())
+                .collect()
+        );
+    }
+
+    /// Serves TXT records through a minimal imitation of the Google Public DNS JSON API,
+    /// preceding each answer with a CNAME. Also returns the user agents of the requests.
+    #[cfg(feature = "google-dns")]
+    async fn spawn_google_dns_server(
+        records: HashMap<String, String>,
+    ) -> (std::net::SocketAddr, Arc<Mutex<Vec<String>>>) {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let user_agents = Arc::new(Mutex::new(vec![]));
+        tokio::spawn({
+            let user_agents = user_agents.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let records = records.clone();
+                    let user_agents = user_agents.clone();
+                    tokio::spawn(async move {
+                        let mut request = vec![];
+                        let mut buf = [0; 1024];
+                        while !request.ends_with(b"\r\n\r\n") {
+                            let n = stream.read(&mut buf).await.unwrap();
+                            request.extend_from_slice(&buf[..n]);
+                        }
+                        let request = String::from_utf8(request).unwrap();
+                        user_agents.lock().unwrap().extend(
+                            request
+                                .lines()
+                                .filter_map(|line| line.strip_prefix("user-agent: "))
+                                .map(ToString::to_string),
+                        );
+                        let name = request
+                            .split(&['?', '&', ' '][..])
+                            .find_map(|param| param.strip_prefix("name="))
+                            .unwrap();
+                        let body = match records.get(name) {
+                            Some(txt) => format!(
+                                r#"{{"Status":0,"Answer":[{{"type":5,"data":"alias."}},{{"type":16,"data":"{}"}}]}}"#,
+                                txt
+                            ),
+                            None => r#"{"Status":3}"#.to_string(),
+                        };
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    });
+                }
+            }
+        });
+
+        (addr, user_agents)
+    }
+
+    #[cfg(feature = "google-dns")]
+    #[tokio::test]
+    async fn google_dns_backend() {
+        let (addr, user_agents) =
+            spawn_google_dns_server(test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS)).await;
+        let mut backend = GoogleDnsBackend::new();
+        backend.with_endpoint(format!("http://{}/resolve", addr));
+        assert_eq!(
+            backend
+                .get_record("nx.example.org".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        backend.with_user_agent("crawler/1.0");
+        assert_eq!(
+            backend
+                .get_record("nx.example.org".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            *user_agents.lock().unwrap(),
+            vec![
+                format!("dnsdisc/{}", env!("CARGO_PKG_VERSION")),
+                "crawler/1.0".to_string()
+            ]
+        );
+        let backend = Arc::new(backend);
+
+        let res = Resolver::<_, SigningKey>::new(backend)
+            .query(EIP_DOMAIN, None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+    }
+
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test]
+    async fn custom_port() {
+        let addr = spawn_dns_server(test_records_to_hashmap(EIP_DOMAIN, EIP_RECORDS)).await;
+        assert_ne!(addr.port(), 53);
+
+        let resolver = tuned_resolver_for_servers(&[addr]).unwrap();
+        let res = Resolver::<_, SigningKey>::new(Arc::new(resolver))
+            .query(EIP_DOMAIN, None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
     }
 }
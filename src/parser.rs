@@ -0,0 +1,82 @@
+//! `nom` parsers splitting record bodies into their fields. Decoding and validation of the
+//! fields themselves is left to `DnsRecord::parse`.
+
+use nom::{
+    bytes::complete::{take_till, take_till1, take_while},
+    character::complete::{char, multispace0, multispace1},
+    combinator::{all_consuming, rest},
+    error::{context, convert_error, VerboseError},
+    multi::separated_list0,
+    sequence::{delimited, separated_pair},
+    Finish, IResult,
+};
+
+pub(crate) type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
+
+/// Single `key=value` entry of a root record.
+pub(crate) fn root_entry_parser(input: &str) -> ParseResult<'_, (&str, &str)> {
+    context(
+        "root entry",
+        separated_pair(
+            take_till1(|c: char| c == '=' || c.is_whitespace()),
+            char('='),
+            take_till(char::is_whitespace),
+        ),
+    )(input)
+}
+
+/// Whitespace-separated entries following the root prefix.
+pub(crate) fn root_record_parser(input: &str) -> ParseResult<'_, Vec<(&str, &str)>> {
+    context(
+        "root record",
+        all_consuming(delimited(
+            multispace0,
+            separated_list0(multispace1, root_entry_parser),
+            multispace0,
+        )),
+    )(input)
+}
+
+/// `<public key>@<domain>` following the link prefix.
+pub(crate) fn link_record_parser(input: &str) -> ParseResult<'_, (&str, &str)> {
+    context(
+        "link record",
+        separated_pair(take_till1(|c| c == '@'), char('@'), rest),
+    )(input)
+}
+
+/// Comma-separated child hashes following the branch prefix. Empty children are kept.
+pub(crate) fn branch_record_parser(input: &str) -> ParseResult<'_, Vec<&str>> {
+    context(
+        "branch record",
+        all_consuming(delimited(
+            multispace0,
+            separated_list0(
+                char(','),
+                take_till(|c: char| c == ',' || c.is_whitespace()),
+            ),
+            multispace0,
+        )),
+    )(input)
+}
+
+/// Base64 payload following the ENR prefix, in either the URL-safe or the standard alphabet.
+pub(crate) fn enr_record_parser(input: &str) -> ParseResult<'_, &str> {
+    context(
+        "ENR record",
+        all_consuming(take_while(|c: char| {
+            c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '/' | '=')
+        })),
+    )(input)
+}
+
+/// Runs `parser` over `input`, rendering errors with their parser context.
+pub(crate) fn run<'a, T>(
+    input: &'a str,
+    parser: impl FnOnce(&'a str) -> ParseResult<'a, T>,
+) -> anyhow::Result<T> {
+    parser(input)
+        .finish()
+        .map(|(_, v)| v)
+        .map_err(|e| anyhow::anyhow!("Invalid record:\n{}", convert_error(input, e)))
+}
@@ -0,0 +1,162 @@
+//! Building blocks of `Resolver`, for crawlers that schedule lookups and store results
+//! themselves.
+//!
+//! This API is lower level than `Resolver` and is more likely to change between releases.
+//! Records are parsed with the EIP-1459 scheme and checked against `DEFAULT_MAX_TXT_SIZE`.
+
+use crate::{
+    check_txt_size, record_hash, Backend, Base32Hash, DnsRecord, HashMismatch, RecordKind,
+    RootRecord, WrongRecordKind, DEFAULT_MAX_TXT_SIZE,
+};
+use anyhow::anyhow;
+use educe::Educe;
+use enr::{EnrKey, EnrKeyUnambiguous};
+
+/// A record below a tree root that hashes to the label it was published under.
+#[derive(Clone, Educe)]
+#[educe(Debug)]
+pub struct VerifiedRecord<K: EnrKey, A: EnrKeyUnambiguous = K> {
+    pub fqdn: String,
+    pub label: Base32Hash,
+    /// Text of the record as published.
+    pub text: String,
+    pub record: DnsRecord<K, A>,
+}
+
+/// Checks that `text`, found at `fqdn`, hashes to `label`.
+pub fn verify_hash(fqdn: &str, label: &str, text: &str) -> Result<(), HashMismatch> {
+    let found = record_hash(text);
+    if !found.eq_ignore_ascii_case(label) {
+        return Err(HashMismatch {
+            fqdn: fqdn.to_string(),
+            found,
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks the signature of `root` against the public key of its tree.
+pub fn verify_root<A: EnrKeyUnambiguous>(
+    root: &RootRecord,
+    public_key: &A::PublicKey,
+) -> anyhow::Result<()> {
+    root.verify::<A>(public_key)
+}
+
+/// Fetches the root of `domain`, verifying it if `public_key` is given. `None` if `domain` has
+/// no record.
+pub async fn fetch_root<A: EnrKeyUnambiguous>(
+    backend: &dyn Backend,
+    domain: &str,
+    public_key: Option<&A::PublicKey>,
+) -> anyhow::Result<Option<RootRecord>> {
+    let text = backend.get_record(domain.to_string()).await?;
+    let text = match check_txt_size(domain.to_string(), text, DEFAULT_MAX_TXT_SIZE)? {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+
+    check_root::<A, A>(text.parse()?, public_key).map(Some)
+}
+
+/// Checks that `record` is a root, verifying it if `public_key` is given.
+pub(crate) fn check_root<K: EnrKey, A: EnrKeyUnambiguous>(
+    record: DnsRecord<K, A>,
+    public_key: Option<&A::PublicKey>,
+) -> anyhow::Result<RootRecord> {
+    let root = record
+        .try_into_root()
+        .map_err(|record| anyhow!("Expected root, got {:?}", record))?;
+    if let Some(public_key) = public_key {
+        verify_root::<A>(&root, public_key)?;
+    }
+
+    Ok(root)
+}
+
+/// Fetches the record published under `label` below `domain` and checks it against the
+/// label. `None` if there is no such record.
+pub async fn fetch_record<K: EnrKey, A: EnrKeyUnambiguous>(
+    backend: &dyn Backend,
+    domain: &str,
+    label: Base32Hash,
+) -> anyhow::Result<Option<VerifiedRecord<K, A>>> {
+    let fqdn = format!("{}.{}", label, domain);
+    let text = backend.get_record(fqdn.clone()).await?;
+    let text = match check_txt_size(fqdn.clone(), text, DEFAULT_MAX_TXT_SIZE)? {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+
+    verify_record(fqdn, label, text, str::parse).map(Some)
+}
+
+/// Checks `text`, found at `fqdn`, against `label` and parses it with `parse`.
+pub(crate) fn verify_record<K: EnrKey, A: EnrKeyUnambiguous>(
+    fqdn: String,
+    label: Base32Hash,
+    text: String,
+    parse: impl FnOnce(&str) -> anyhow::Result<DnsRecord<K, A>>,
+) -> anyhow::Result<VerifiedRecord<K, A>> {
+    verify_hash(&fqdn, &label, &text)?;
+    let record = parse(&text)?;
+
+    Ok(VerifiedRecord {
+        fqdn,
+        label,
+        text,
+        record,
+    })
+}
+
+/// Labels of the children of a branch record. `None` for other kinds of records.
+pub fn expand_branch<K: EnrKey, A: EnrKeyUnambiguous>(
+    record: &DnsRecord<K, A>,
+) -> Option<Vec<Base32Hash>> {
+    match record {
+        DnsRecord::Branch { children } => {
+            let mut children = children.iter().copied().collect::<Vec<_>>();
+            children.sort_unstable();
+            Some(children)
+        }
+        _ => None,
+    }
+}
+
+/// Checks that a record is of the kind expected below a subtree root: a branch, or a leaf of
+/// `leaf_kind`. `parent` is the branch that listed the record, if any.
+pub fn expect_kind<K: EnrKey, A: EnrKeyUnambiguous>(
+    record: &VerifiedRecord<K, A>,
+    parent: Option<Base32Hash>,
+    leaf_kind: RecordKind,
+) -> Result<(), WrongRecordKind> {
+    check_kind(
+        &record.fqdn,
+        record.label,
+        parent,
+        record.record.kind(),
+        leaf_kind,
+    )
+}
+
+/// Like `expect_kind`, for a record of kind `found` at `fqdn`.
+pub(crate) fn check_kind(
+    fqdn: &str,
+    label: Base32Hash,
+    parent: Option<Base32Hash>,
+    found: RecordKind,
+    leaf_kind: RecordKind,
+) -> Result<(), WrongRecordKind> {
+    if found != RecordKind::Branch && found != leaf_kind {
+        return Err(WrongRecordKind {
+            fqdn: fqdn.to_string(),
+            label,
+            parent,
+            expected: leaf_kind,
+            found,
+        });
+    }
+
+    Ok(())
+}
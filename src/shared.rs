@@ -0,0 +1,210 @@
+//! Fan-out of a single query to several consumers.
+
+use crate::QueryStream;
+use enr::{Enr, EnrKey};
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+use tokio_stream::Stream;
+
+/// Result of a shared query. Errors are shared between consumers like ENRs are.
+pub type SharedItem<K> = Result<Arc<Enr<K>>, Arc<anyhow::Error>>;
+
+/// What a `SharedQuery` does when a consumer falls `buffer` items behind the fastest one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Keep crawling and drop the oldest buffered items. The lagging consumer skips them,
+    /// see `SharedQuery::skipped`.
+    #[default]
+    DropOldest,
+    /// Stop pulling from the crawl until the slowest consumer catches up.
+    Backpressure,
+}
+
+/// Turns a query into a `SharedQuery`.
+pub trait QueryExt<K: EnrKey> {
+    /// Shares the query, buffering up to `buffer` items for lagging consumers and dropping the
+    /// oldest ones past that.
+    fn into_shared(self, buffer: usize) -> SharedQuery<K>;
+
+    /// Like `into_shared`, but with the given policy for consumers that fall behind.
+    fn into_shared_with(self, buffer: usize, lag_policy: LagPolicy) -> SharedQuery<K>;
+}
+
+impl<K: EnrKey> QueryExt<K> for QueryStream<K> {
+    fn into_shared(self, buffer: usize) -> SharedQuery<K> {
+        SharedQuery::new(self, buffer, LagPolicy::default())
+    }
+
+    fn into_shared_with(self, buffer: usize, lag_policy: LagPolicy) -> SharedQuery<K> {
+        SharedQuery::new(self, buffer, lag_policy)
+    }
+}
+
+struct Inner<K: EnrKey> {
+    query: QueryStream<K>,
+    done: bool,
+    /// Items not yet seen by every consumer. `buffer[0]` is item number `start`.
+    buffer: VecDeque<SharedItem<K>>,
+    start: usize,
+    capacity: usize,
+    lag_policy: LagPolicy,
+    /// Position of each live consumer, by consumer id.
+    cursors: HashMap<usize, usize>,
+    next_id: usize,
+    waiting: Vec<Waker>,
+}
+
+impl<K: EnrKey> Inner<K> {
+    fn register(&mut self, cursor: usize) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.cursors.insert(id, cursor);
+        id
+    }
+
+    /// Drops items that every consumer has seen, waking consumers waiting for room.
+    fn trim(&mut self) {
+        let min = self
+            .cursors
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.start + self.buffer.len());
+        let mut trimmed = false;
+        while self.start < min && self.buffer.pop_front().is_some() {
+            self.start += 1;
+            trimmed = true;
+        }
+        if trimmed && self.lag_policy == LagPolicy::Backpressure {
+            self.wake_all();
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Cloneable handle to a query. Each clone is a stream of its own, starting at the position
+/// of the handle it was cloned from, and all of them are fed by one crawl. The crawl starts
+/// when any of them is first polled, and is cancelled once all of them are dropped.
+pub struct SharedQuery<K: EnrKey> {
+    inner: Arc<Mutex<Inner<K>>>,
+    id: usize,
+    skipped: usize,
+}
+
+impl<K: EnrKey> SharedQuery<K> {
+    /// Shares `query` between consumers. Up to `buffer` items not yet seen by every consumer
+    /// are kept, and `lag_policy` decides what happens once a consumer falls further behind.
+    /// `QueryExt` offers the same as a method on the query.
+    pub fn new(query: QueryStream<K>, buffer: usize, lag_policy: LagPolicy) -> Self {
+        let mut inner = Inner {
+            query,
+            done: false,
+            buffer: VecDeque::new(),
+            start: 0,
+            capacity: buffer.max(1),
+            lag_policy,
+            cursors: HashMap::new(),
+            next_id: 0,
+            waiting: vec![],
+        };
+        let id = inner.register(0);
+
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            id,
+            skipped: 0,
+        }
+    }
+
+    /// Number of items this consumer missed under `LagPolicy::DropOldest`.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl<K: EnrKey> Clone for SharedQuery<K> {
+    fn clone(&self) -> Self {
+        let mut inner = self.inner.lock().unwrap();
+        let cursor = inner.cursors[&self.id];
+        let id = inner.register(cursor);
+
+        Self {
+            inner: self.inner.clone(),
+            id,
+            skipped: self.skipped,
+        }
+    }
+}
+
+impl<K: EnrKey> Drop for SharedQuery<K> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.cursors.remove(&self.id);
+        inner.trim();
+        // This consumer may be the one the crawl would have woken.
+        inner.wake_all();
+    }
+}
+
+impl<K: EnrKey> Stream for SharedQuery<K> {
+    type Item = SharedItem<K>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut inner = this.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        let mut cursor = inner.cursors[&this.id];
+        if cursor < inner.start {
+            this.skipped += inner.start - cursor;
+            cursor = inner.start;
+            inner.cursors.insert(this.id, cursor);
+        }
+
+        if cursor == inner.start + inner.buffer.len() {
+            if inner.done {
+                return Poll::Ready(None);
+            }
+
+            if inner.lag_policy == LagPolicy::Backpressure && inner.buffer.len() >= inner.capacity {
+                inner.waiting.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            match inner.query.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    inner.buffer.push_back(item.map(Arc::new).map_err(Arc::new));
+                    if inner.buffer.len() > inner.capacity {
+                        inner.buffer.pop_front();
+                        inner.start += 1;
+                    }
+                }
+                Poll::Ready(None) => inner.done = true,
+                Poll::Pending => {
+                    inner.waiting.push(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+            inner.wake_all();
+
+            if inner.done {
+                return Poll::Ready(None);
+            }
+        }
+
+        let item = inner.buffer[cursor - inner.start].clone();
+        inner.cursors.insert(this.id, cursor + 1);
+        inner.trim();
+
+        Poll::Ready(Some(item))
+    }
+}